@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::auth::{Role, User};
+
+/// A folder restricts who can see and change the pages in it, and in its
+/// subfolders, with this file. It has a `read:` and a `write:` line, each
+/// listing user names, `@group` names, or `*` for everyone:
+///
+/// ```text
+/// read: @hr, alice
+/// write: @hr
+/// ```
+///
+/// A missing `read:` line lets everyone read, and a missing `write:` line lets
+/// everyone who can read also write. Those listed under `write:` can always
+/// read. The access file closest to a page is the one that applies.
+pub const ACCESS_FILE: &str = ".access";
+
+#[derive(Default, Debug, PartialEq)]
+struct Rules {
+    read: Option<Vec<String>>,
+    write: Option<Vec<String>>,
+}
+
+impl Rules {
+    /// Lets nobody but admins in. Used for access files that can't be read,
+    /// so that a typo never opens a folder up.
+    fn deny_all() -> Self {
+        Rules {
+            read: Some(vec![]),
+            write: Some(vec![]),
+        }
+    }
+
+    fn parse(content: &str) -> Option<Self> {
+        let mut rules = Rules::default();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once(':')?;
+            let principals = value
+                .split(',')
+                .map(|principal| principal.trim().to_string())
+                .filter(|principal| !principal.is_empty())
+                .collect();
+
+            match key.trim() {
+                "read" => rules.read = Some(principals),
+                "write" => rules.write = Some(principals),
+                _ => return None,
+            }
+        }
+        Some(rules)
+    }
+}
+
+fn matches(principals: &[String], user: &User) -> bool {
+    principals.iter().any(|principal| {
+        if principal == "*" {
+            true
+        } else if let Some(group) = principal.strip_prefix('@') {
+            user.groups.iter().any(|g| g == group)
+        } else {
+            user.name.as_deref() == Some(principal.as_str())
+        }
+    })
+}
+
+/// Checks what a user may do with the files in the wiki. The access files are
+/// read once per folder, so create one per request.
+pub struct Access<'a> {
+    wiki_root: &'a Path,
+    user: &'a User,
+    rules: RefCell<HashMap<PathBuf, Option<Rc<Rules>>>>,
+}
+
+impl<'a> Access<'a> {
+    pub fn new(wiki_root: &'a Path, user: &'a User) -> Self {
+        Access {
+            wiki_root,
+            user,
+            rules: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn user(&self) -> &User {
+        self.user
+    }
+
+    /// The rules of the closest access file in `folder` or its parents.
+    fn rules(&self, folder: &Path) -> Option<Rc<Rules>> {
+        if let Some(rules) = self.rules.borrow().get(folder) {
+            return rules.clone();
+        }
+
+        let rules = match fs::read_to_string(self.wiki_root.join(folder).join(ACCESS_FILE)) {
+            Ok(content) => Some(Rc::new(Rules::parse(&content).unwrap_or_else(|| {
+                println!("Invalid access file in '{}'", folder.display());
+                Rules::deny_all()
+            }))),
+            Err(_) => folder.parent().and_then(|parent| self.rules(parent)),
+        };
+
+        self.rules
+            .borrow_mut()
+            .insert(folder.to_path_buf(), rules.clone());
+        rules
+    }
+
+    /// `path` is a page or file, relative to the wiki root.
+    fn rules_for(&self, path: &Path) -> Option<Rc<Rules>> {
+        let path = PathBuf::from(path.to_string_lossy().replace('\\', "/"));
+        self.rules(path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    pub fn can_read(&self, path: &Path) -> bool {
+        if self.user.role >= Role::Admin {
+            return true;
+        }
+
+        match self.rules_for(path) {
+            Some(rules) => {
+                let can_read = rules.read.as_ref().map_or(true, |p| matches(p, self.user));
+                let can_write = rules
+                    .write
+                    .as_ref()
+                    .map_or(false, |p| matches(p, self.user));
+                can_read || can_write
+            }
+            None => true,
+        }
+    }
+
+    /// Whether the folder rules let the user change `path`. The role of the
+    /// user is checked separately, by the route guards.
+    pub fn can_write(&self, path: &Path) -> bool {
+        if self.user.role >= Role::Admin {
+            return true;
+        }
+
+        match self.rules_for(path) {
+            Some(rules) => match rules.write {
+                Some(ref principals) => matches(principals, self.user),
+                None => self.can_read(path),
+            },
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn user(name: &str, groups: &[&str]) -> User {
+        User {
+            name: Some(name.to_string()),
+            role: Role::Editor,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = Rules::parse("# HR only\nread: @hr, alice\nwrite:\n").unwrap();
+        assert_eq!(
+            rules,
+            Rules {
+                read: Some(vec!["@hr".to_string(), "alice".to_string()]),
+                write: Some(vec![]),
+            }
+        );
+        assert_eq!(Rules::parse("reed: alice"), None);
+    }
+
+    #[test]
+    fn closest_access_file_applies() {
+        let root = env::temp_dir().join(format!("simplewiki-acl-{}", process::id()));
+        fs::create_dir_all(root.join("hr/open")).unwrap();
+        fs::write(root.join("hr").join(ACCESS_FILE), "read: @hr\nwrite: carol").unwrap();
+        fs::write(root.join("hr/open").join(ACCESS_FILE), "read: *").unwrap();
+
+        let alice = user("alice", &["hr"]);
+        let bob = user("bob", &[]);
+        let carol = user("carol", &[]);
+        let admin = User {
+            role: Role::Admin,
+            ..user("admin", &[])
+        };
+
+        let page = Path::new("hr/salaries");
+        assert!(Access::new(&root, &alice).can_read(page));
+        assert!(!Access::new(&root, &alice).can_write(page));
+        assert!(!Access::new(&root, &bob).can_read(page));
+        assert!(Access::new(&root, &carol).can_read(page));
+        assert!(Access::new(&root, &carol).can_write(page));
+        assert!(Access::new(&root, &admin).can_write(page));
+
+        // Subfolders inherit, unless they have their own access file.
+        assert!(!Access::new(&root, &bob).can_read(Path::new("hr/2024/review.png")));
+        assert!(Access::new(&root, &bob).can_read(Path::new("hr/open/holidays")));
+        assert!(Access::new(&root, &bob).can_write(Path::new("hr/open/holidays")));
+        assert!(Access::new(&root, &bob).can_write(Path::new("home")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+use crate::errors::*;
+use crate::search::{self, SearchMatchContext, SearchMatchText};
+
+/// Version of the JSON API, which is part of its routes. Fields may be added
+/// to a version, but not removed or changed.
+pub const VERSION: u32 = 1;
+
+/// Titles returned for autocompletion, unless asked for otherwise.
+pub const DEFAULT_TITLES: usize = 10;
+
+/// A search, as returned by `/api/v1/search`.
+#[derive(Serialize)]
+pub struct SearchResponse {
+    pub version: u32,
+    pub query: String,
+    /// Milliseconds the search took.
+    pub elapsed: i64,
+    pub total: usize,
+    pub next_offset: Option<usize>,
+    pub truncated: bool,
+    pub skipped: usize,
+    pub titles: Vec<Title>,
+    pub matches: Vec<FileMatch>,
+}
+
+#[derive(Serialize)]
+pub struct Title {
+    pub page: String,
+    pub url: String,
+    pub heading: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FileMatch {
+    pub page: String,
+    /// Relative to the wiki, such as `/folder/page`.
+    pub url: String,
+    /// Absolute path of the markdown file, for opening it in an editor.
+    pub file_path: String,
+    pub score: f32,
+    /// Matching lines with the lines around them.
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Serialize)]
+pub struct Block {
+    /// Anchor of the heading the block is under.
+    pub anchor: Option<String>,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Serialize)]
+pub struct Line {
+    pub line_number: i32,
+    pub text: String,
+    /// Empty for the lines around a match.
+    pub matches: Vec<Span>,
+}
+
+/// Byte offsets of a match in its line.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    version: u32,
+    error: String,
+}
+
+impl ErrorResponse {
+    fn new(error: String) -> Self {
+        ErrorResponse {
+            version: VERSION,
+            error,
+        }
+    }
+}
+
+fn url(page_url: &str) -> String {
+    format!("/{}", page_url.trim_start_matches('/'))
+}
+
+fn line(context: &SearchMatchContext) -> Line {
+    let mut text = String::new();
+    let mut matches = vec![];
+    for part in &context.lines {
+        match part {
+            SearchMatchText::Text(part) => text.push_str(part),
+            SearchMatchText::Match(part) => {
+                let start = text.len();
+                text.push_str(part);
+                matches.push(Span {
+                    start,
+                    end: text.len(),
+                });
+            }
+        }
+    }
+
+    Line {
+        line_number: context.line_number,
+        text,
+        matches,
+    }
+}
+
+impl SearchResponse {
+    pub fn new(result: &search::SearchResult) -> Self {
+        SearchResponse {
+            version: VERSION,
+            query: result.pattern.clone(),
+            elapsed: result.elapsed,
+            total: result.total,
+            next_offset: result.next_offset,
+            truncated: result.truncated,
+            skipped: result.skipped,
+            titles: titles(&result.titles),
+            matches: result
+                .matches
+                .iter()
+                .map(|file_match| FileMatch {
+                    page: file_match.page.clone(),
+                    url: url(&file_match.url),
+                    file_path: fs::canonicalize(&file_match.file_path)
+                        .unwrap_or_else(|_| file_match.file_path.clone())
+                        .to_string_lossy()
+                        .into_owned(),
+                    score: file_match.score,
+                    blocks: file_match
+                        .contexts
+                        .iter()
+                        .map(|block| Block {
+                            anchor: block.anchor.clone(),
+                            lines: block.contexts.iter().map(line).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn titles(titles: &[search::TitleMatch]) -> Vec<Title> {
+    titles
+        .iter()
+        .map(|title| Title {
+            page: title.page.clone(),
+            url: url(&title.url),
+            heading: title.heading.clone(),
+        })
+        .collect()
+}
+
+/// Paths of the API, which get JSON errors rather than HTML pages.
+pub fn is_api(path: &str) -> bool {
+    Path::new(path).starts_with("/api")
+}
+
+/// An error sent as JSON. An API client that isn't logged in gets one
+/// instead of being redirected to the login page, so that it can retry with
+/// basic authentication.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: &str) -> Self {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        ApiError::new(Status::InternalServerError, &error.to_string())
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let body = serde_json::to_string(&ErrorResponse::new(self.message))
+            .map_err(|_| Status::InternalServerError)?;
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(body));
+        if self.status == Status::Unauthorized {
+            response.header(Header::new(
+                "WWW-Authenticate",
+                "Basic realm=\"simplewiki\", charset=\"UTF-8\"",
+            ));
+        }
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_offsets_are_bytes_in_the_line() {
+        let context = SearchMatchContext {
+            line_number: 4,
+            lines: vec![
+                SearchMatchText::Text("blå ".to_string()),
+                SearchMatchText::Match("foo".to_string()),
+                SearchMatchText::Text(" and ".to_string()),
+                SearchMatchText::Match("bar".to_string()),
+            ],
+        };
+
+        let line = line(&context);
+        assert_eq!(line.text, "blå foo and bar");
+        assert_eq!(
+            line.matches,
+            vec![Span { start: 5, end: 8 }, Span { start: 13, end: 16 }]
+        );
+        assert_eq!(&line.text[5..8], "foo");
+    }
+
+    #[test]
+    fn api_paths() {
+        assert!(is_api("/api/v1/search"));
+        assert!(!is_api("/apiary"));
+        assert!(!is_api("/search"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// `a/b.md` is backed up to `a/b.md.bak`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".bak");
+    path.with_file_name(file_name)
+}
+
+/// A hidden file next to `path`, so that the rename stays on one file system.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let counter = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    path.with_file_name(format!(".{}.{}.{}.tmp", file_name, process::id(), counter))
+}
+
+fn write_temp(temp: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Makes the rename itself durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Writes `content` to `path` so that the file either has its old or its new
+/// content, even if the process crashes or the disk fills up half way. The
+/// content is written to a temporary file that replaces `path` when it is
+/// complete. With `keep_backup`, the previous content is kept in a `.bak` file.
+pub fn write(path: &Path, content: &[u8], keep_backup: bool) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let temp = temp_path(path);
+    if let Err(e) = write_temp(&temp, content) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    // Keep the permissions of the file we replace.
+    if let Ok(metadata) = fs::metadata(path) {
+        let _ = fs::set_permissions(&temp, metadata.permissions());
+    }
+
+    if keep_backup && path.exists() {
+        if let Err(e) = fs::copy(path, backup_path(path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+    }
+
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    sync_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn backup_is_next_to_file() {
+        assert_eq!(
+            backup_path(Path::new("wiki/a/b.md")),
+            PathBuf::from("wiki/a/b.md.bak")
+        );
+    }
+
+    #[test]
+    fn replaces_content_and_keeps_backup() {
+        let dir = env::temp_dir().join(format!("simplewiki-atomic-{}", process::id()));
+        let path = dir.join("page.md");
+
+        write(&path, b"first", true).unwrap();
+        assert!(!backup_path(&path).exists());
+
+        write(&path, b"second", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"first");
+
+        // Only the page and its backup are left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use multipart::server::Multipart;
+use rocket::http::uri::Uri;
+
+use crate::errors::*;
+
+/// Uploads larger than this are rejected.
+pub const MAX_UPLOAD_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Requests uploading more files than this, or more bytes in total, are
+/// rejected.
+pub const MAX_FILES: usize = 20;
+pub const MAX_REQUEST_SIZE: u64 = 100 * 1024 * 1024;
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp", "bmp"];
+
+/// A stored attachment, and the markdown the editor should insert for it.
+#[derive(Serialize)]
+pub struct Attachment {
+    pub file_name: String,
+    pub url: String,
+    pub markdown: String,
+}
+
+/// Attachments of `a/b.md` are stored in `a/b.attachments/`.
+pub fn attachments_dir(markdown_file: &Path) -> PathBuf {
+    markdown_file.with_extension("attachments")
+}
+
+/// Strips any directories from an uploaded file name and replaces characters
+/// that would need escaping in a markdown link.
+pub fn safe_file_name(name: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+
+    if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Appends `-1`, `-2`, ... to the file stem until the name is not taken.
+fn unique_file_name(dir: &Path, name: &str) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 => (&name[..index], &name[index..]),
+        _ => (name, ""),
+    };
+
+    let mut candidate = name.to_string();
+    let mut counter = 1;
+    while dir.join(&candidate).exists() {
+        candidate = format!("{}-{}{}", stem, counter, extension);
+        counter += 1;
+    }
+    candidate
+}
+
+/// Percent-encodes a part of the link to an attachment, including the
+/// parentheses that would end a markdown link.
+fn url_segment(segment: &str) -> String {
+    Uri::percent_encode(segment)
+        .replace('(', "%28")
+        .replace(')', "%29")
+}
+
+fn is_image(file_name: &str) -> bool {
+    Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Stores one uploaded file next to the page in `markdown_file`.
+pub fn save<R: Read>(markdown_file: &Path, file_name: &str, data: R) -> Result<Attachment> {
+    let dir = attachments_dir(markdown_file);
+    fs::create_dir_all(&dir).chain_err(|| "Failed to create attachments folder")?;
+
+    let file_name = unique_file_name(&dir, &safe_file_name(file_name));
+    let path = dir.join(&file_name);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .chain_err(|| "Failed to create attachment")?;
+
+    let written = match io::copy(&mut data.take(MAX_UPLOAD_SIZE + 1), &mut file) {
+        Ok(written) => written,
+        Err(e) => {
+            drop(file);
+            let _ = fs::remove_file(&path);
+            return Err(e).chain_err(|| "Failed to write attachment");
+        }
+    };
+    if written > MAX_UPLOAD_SIZE {
+        drop(file);
+        let _ = fs::remove_file(&path);
+        bail!("Attachment is larger than {} bytes", MAX_UPLOAD_SIZE);
+    }
+
+    // Links are relative to the folder of the page.
+    let dir_name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .chain_err(|| "Invalid page name")?;
+    let url = format!("{}/{}", url_segment(dir_name), url_segment(&file_name));
+
+    let markdown = if is_image(&file_name) {
+        format!("![{}]({})", file_name, url)
+    } else {
+        format!("[{}]({})", file_name, url)
+    };
+
+    Ok(Attachment {
+        file_name,
+        url,
+        markdown,
+    })
+}
+
+fn save_entries<R: Read>(
+    markdown_file: &Path,
+    boundary: &str,
+    body: R,
+    attachments: &mut Vec<Attachment>,
+) -> Result<()> {
+    let mut multipart = Multipart::with_body(body, boundary);
+    while let Some(entry) = multipart
+        .read_entry()
+        .chain_err(|| "Invalid multipart body")?
+    {
+        if let Some(file_name) = entry.headers.filename.clone() {
+            if attachments.len() == MAX_FILES {
+                bail!("An upload can have at most {} files", MAX_FILES);
+            }
+            attachments.push(save(markdown_file, &file_name, entry.data)?);
+        }
+    }
+    Ok(())
+}
+
+/// Stores every file in a `multipart/form-data` body. Either all of them are
+/// stored, or none are.
+pub fn save_multipart<R: Read>(
+    markdown_file: &Path,
+    boundary: &str,
+    body: R,
+) -> Result<Vec<Attachment>> {
+    let mut body = body.take(MAX_REQUEST_SIZE + 1);
+    let mut attachments = vec![];
+    let mut result = save_entries(markdown_file, boundary, &mut body, &mut attachments);
+    if body.limit() == 0 {
+        result = Err(format!("Upload is larger than {} bytes", MAX_REQUEST_SIZE).into());
+    }
+
+    if let Err(e) = result {
+        let dir = attachments_dir(markdown_file);
+        for attachment in &attachments {
+            let _ = fs::remove_file(dir.join(&attachment.file_name));
+        }
+        return Err(e);
+    }
+    Ok(attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_sanitized() {
+        assert_eq!(safe_file_name("screen shot.png"), "screen_shot.png");
+        assert_eq!(safe_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_file_name("C:\\Users\\me\\a.txt"), "a.txt");
+        assert_eq!(safe_file_name(".hidden"), "hidden");
+        assert_eq!(safe_file_name(""), "attachment");
+    }
+
+    #[test]
+    fn links_work_for_any_page_name() {
+        let root = std::env::temp_dir().join(format!("simplewiki-attach-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let attachment = save(&root.join("my page (v2).md"), "a.png", &b"png"[..]).unwrap();
+        assert_eq!(attachment.url, "my%20page%20%28v2%29.attachments/a.png");
+        assert_eq!(
+            attachment.markdown,
+            "![a.png](my%20page%20%28v2%29.attachments/a.png)"
+        );
+        assert!(root.join("my page (v2).attachments/a.png").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn uploads_with_too_many_files_store_nothing() {
+        let root = std::env::temp_dir().join(format!("simplewiki-upload-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let page = root.join("page.md");
+
+        let body = |files: usize| {
+            let mut body = String::new();
+            for i in 0..files {
+                body.push_str(&format!(
+                    "--b\r\nContent-Disposition: form-data; name=\"file\"; \
+                     filename=\"{}.txt\"\r\n\r\ntext\r\n",
+                    i
+                ));
+            }
+            body + "--b--\r\n"
+        };
+
+        let uploaded = save_multipart(&page, "b", body(2).as_bytes()).unwrap();
+        assert_eq!(uploaded.len(), 2);
+        fs::remove_dir_all(attachments_dir(&page)).unwrap();
+
+        assert!(save_multipart(&page, "b", body(MAX_FILES + 1).as_bytes()).is_err());
+        assert_eq!(fs::read_dir(attachments_dir(&page)).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn attachments_live_next_to_the_page() {
+        assert_eq!(
+            attachments_dir(Path::new("wiki/a/b.md")),
+            PathBuf::from("wiki/a/b.attachments")
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use sha2::{Digest, Sha256};
+
+use crate::errors::*;
+
+/// Every change to the wiki is appended to this file, relative to the wiki
+/// root, as one JSON object per line.
+pub const AUDIT_LOG: &str = ".simplewiki/audit.log";
+
+/// Keeps lines from concurrent requests from being interleaved.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Save,
+    Delete,
+    Upload,
+    Restore,
+    Purge,
+    /// Saved by find and replace.
+    Replace,
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Option<Action> {
+        match action {
+            "save" => Some(Action::Save),
+            "delete" => Some(Action::Delete),
+            "upload" => Some(Action::Upload),
+            "restore" => Some(Action::Restore),
+            "purge" => Some(Action::Purge),
+            "replace" => Some(Action::Replace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub action: Action,
+    /// `None` when authentication is disabled.
+    pub user: Option<String>,
+    pub address: Option<String>,
+    pub page: String,
+    /// SHA-256 of the file before and after the change. An uploaded file is
+    /// hashed on its own.
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    /// The name of an uploaded file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: Action, page: &str, user: Option<&str>, address: &ClientAddress) -> Self {
+        AuditEntry {
+            time: Utc::now(),
+            action,
+            user: user.map(String::from),
+            address: address.0.clone(),
+            page: page.replace('\\', "/"),
+            old_hash: None,
+            new_hash: None,
+            file_name: None,
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of a file, or `None` if it doesn't exist.
+pub fn file_hash(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    Some(
+        Sha256::digest(bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+pub fn append(wiki_root: &Path, entry: &AuditEntry) -> Result<()> {
+    let line = serde_json::to_string(entry).chain_err(|| "Failed to serialize")?;
+    let path = wiki_root.join(AUDIT_LOG);
+
+    let _lock = LOCK.lock().unwrap();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).chain_err(|| "Failed to create audit log folder")?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .chain_err(|| "Failed to open audit log")?;
+    writeln!(file, "{}", line).chain_err(|| "Failed to write audit log")?;
+    file.sync_data().chain_err(|| "Failed to write audit log")
+}
+
+/// Narrows down the entries shown on the audit page. Empty fields match
+/// everything.
+#[derive(Default)]
+pub struct Filter {
+    pub user: Option<String>,
+    pub action: Option<Action>,
+    /// Part of the page name.
+    pub page: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user
+            .as_ref()
+            .map_or(true, |user| entry.user.as_ref() == Some(user))
+            && self.action.map_or(true, |action| entry.action == action)
+            && self
+                .page
+                .as_ref()
+                .map_or(true, |page| entry.page.contains(page.as_str()))
+    }
+}
+
+/// Returns the newest entries matching `filter`, newest first.
+pub fn read(wiki_root: &Path, filter: &Filter, limit: usize) -> Result<Vec<AuditEntry>> {
+    let file = match fs::File::open(wiki_root.join(AUDIT_LOG)) {
+        Ok(file) => file,
+        Err(_) => return Ok(vec![]),
+    };
+
+    // Only the last `limit` matches are kept while the whole log is read.
+    let mut entries = VecDeque::with_capacity(limit.min(1024));
+    for line in BufReader::new(file).lines() {
+        let line = line.chain_err(|| "Failed to read audit log")?;
+        match serde_json::from_str::<AuditEntry>(&line) {
+            Ok(entry) if filter.matches(&entry) => {
+                if entries.len() == limit {
+                    entries.pop_front();
+                }
+                if limit > 0 {
+                    entries.push_back(entry);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => println!("Skipping invalid audit log line: {}", e),
+        }
+    }
+
+    Ok(entries.into_iter().rev().collect())
+}
+
+/// Reverse proxies whose `X-Real-IP` header gives the address of the client.
+/// Anyone else could send the header to hide their address.
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+/// The address of the client, given the address the request came from and
+/// the `X-Real-IP` header.
+fn client_address(
+    remote: Option<IpAddr>,
+    real_ip: Option<IpAddr>,
+    proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    match remote {
+        Some(remote) if proxies.contains(&remote) => real_ip.or(Some(remote)),
+        remote => remote,
+    }
+}
+
+/// Request guard with the address of the client.
+pub struct ClientAddress(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientAddress {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let proxies = request.guard::<State<TrustedProxies>>()?;
+        let remote = request.remote().map(|address| address.ip());
+        let address = client_address(remote, request.real_ip(), &proxies.0);
+        Outcome::Success(ClientAddress(address.map(|ip| ip.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn real_ip_is_only_trusted_from_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxies = vec![ip("10.0.0.1")];
+        assert_eq!(
+            client_address(Some(ip("10.0.0.1")), Some(ip("1.2.3.4")), &proxies),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            client_address(Some(ip("10.0.0.1")), None, &proxies),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            client_address(Some(ip("5.6.7.8")), Some(ip("1.2.3.4")), &proxies),
+            Some(ip("5.6.7.8"))
+        );
+    }
+
+    #[test]
+    fn entries_are_appended_and_filtered() {
+        let root = env::temp_dir().join(format!("simplewiki-audit-{}", process::id()));
+        let address = ClientAddress(Some("127.0.0.1".to_string()));
+
+        let mut save = AuditEntry::new(Action::Save, "notes\\a", Some("alice"), &address);
+        save.new_hash = Some("abc".to_string());
+        append(&root, &save).unwrap();
+        append(
+            &root,
+            &AuditEntry::new(Action::Delete, "b", Some("bob"), &address),
+        )
+        .unwrap();
+        append(&root, &AuditEntry::new(Action::Save, "b", None, &address)).unwrap();
+
+        let all = read(&root, &Filter::default(), 10).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].user, None);
+        assert_eq!(all[2].page, "notes/a");
+        assert_eq!(all[2].new_hash.as_deref(), Some("abc"));
+
+        let filter = Filter {
+            action: Some(Action::Save),
+            page: Some("b".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(read(&root, &filter, 10).unwrap().len(), 1);
+
+        let filter = Filter {
+            user: Some("alice".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(read(&root, &filter, 10).unwrap()[0].page, "notes/a");
+        let newest = read(&root, &Filter::default(), 2).unwrap();
+        assert_eq!(newest.len(), 2);
+        assert_eq!(newest[0].user, None);
+        assert_eq!(newest[1].user.as_deref(), Some("bob"));
+        assert!(read(&root, &Filter::default(), 0).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
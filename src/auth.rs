@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use rand::RngCore;
+use rocket::http::{Cookie, Cookies, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+
+use crate::api;
+use crate::errors::*;
+
+/// Name of the signed cookie holding the name of the logged in user.
+const SESSION_COOKIE: &str = "user";
+
+/// The key used to sign session cookies is kept here, relative to the wiki
+/// root, so that sessions survive a restart, unless `--secret-key-file`
+/// says otherwise.
+pub const DEFAULT_SECRET_KEY_FILE: &str = ".simplewiki/secret_key";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+pub enum Role {
+    /// Can read pages and search.
+    Viewer,
+    /// Can also create, edit and delete pages.
+    Editor,
+    /// Can do anything, including purging the trash.
+    Admin,
+}
+
+impl Role {
+    fn parse(role: &str) -> Option<Role> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+struct Account {
+    role: Role,
+    password_hash: String,
+    groups: Vec<String>,
+}
+
+/// The users that can log in, read from a users file. Every line of the file
+/// is `name:role:password hash`, where the role is `viewer`, `editor` or
+/// `admin`, and the hash is made with `simplewiki --hash-password`. A
+/// comma-separated list of groups, used by access files, can follow after
+/// another `:`. Empty lines and lines starting with `#` are ignored.
+pub struct Users {
+    accounts: HashMap<String, Account>,
+}
+
+impl Users {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .chain_err(|| format!("Failed to read users file {}", path.display()))?;
+        Users::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut accounts = HashMap::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.splitn(4, ':').collect();
+            let (name, role, password_hash, groups) = match parts[..] {
+                [name, role, hash] if !name.is_empty() => (name, role, hash, ""),
+                [name, role, hash, groups] if !name.is_empty() => (name, role, hash, groups),
+                _ => bail!("Line {} of the users file is not name:role:hash", index + 1),
+            };
+            let role = Role::parse(role)
+                .chain_err(|| format!("Unknown role '{}' on line {}", role, index + 1))?;
+
+            accounts.insert(
+                name.to_string(),
+                Account {
+                    role,
+                    password_hash: password_hash.to_string(),
+                    groups: groups
+                        .split(',')
+                        .map(|group| group.trim().to_string())
+                        .filter(|group| !group.is_empty())
+                        .collect(),
+                },
+            );
+        }
+        Ok(Users { accounts })
+    }
+
+    /// Returns the role of the user if the password is correct.
+    pub fn verify(&self, name: &str, password: &str) -> Option<Role> {
+        let account = self.accounts.get(name)?;
+        match bcrypt::verify(password, &account.password_hash) {
+            Ok(true) => Some(account.role),
+            _ => None,
+        }
+    }
+
+    fn user(&self, name: String) -> Option<User> {
+        let account = self.accounts.get(&name)?;
+        Some(User {
+            name: Some(name),
+            role: account.role,
+            groups: account.groups.clone(),
+        })
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST).chain_err(|| "Failed to hash password")
+}
+
+/// Managed state. Without a users file, authentication is disabled and
+/// everyone is an anonymous admin.
+pub struct Auth {
+    pub users: Option<Users>,
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        self.users.is_some()
+    }
+}
+
+/// Creates a file only its owner can read.
+fn create_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            println!(
+                "Warning: {} can be read by other users, who could log in as anyone",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+/// Reads the key used to sign session cookies from `path`, creating it the
+/// first time.
+pub fn load_secret_key(path: &Path) -> Result<String> {
+    if let Ok(key) = fs::read_to_string(path) {
+        warn_if_readable_by_others(path);
+        return Ok(key.trim().to_string());
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let key = base64::encode(bytes);
+
+    create_private(path, key.as_bytes())
+        .chain_err(|| format!("Failed to store secret key in {}", path.display()))?;
+    Ok(key)
+}
+
+pub fn log_in(cookies: &mut Cookies, name: &str) {
+    cookies.add_private(Cookie::new(SESSION_COOKIE, name.to_string()));
+}
+
+pub fn log_out(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+}
+
+/// The user making a request.
+#[derive(Clone, Debug)]
+pub struct User {
+    /// `None` when authentication is disabled.
+    pub name: Option<String>,
+    pub role: Role,
+    pub groups: Vec<String>,
+}
+
+/// The user of a request, looked up once however many guards ask for it.
+struct CachedUser(Option<User>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for User {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let auth = request.guard::<State<Auth>>()?;
+        let users = match auth.users {
+            Some(ref users) => users,
+            None => {
+                return Outcome::Success(User {
+                    name: None,
+                    role: Role::Admin,
+                    groups: vec![],
+                })
+            }
+        };
+
+        let cached = request.local_cache(|| {
+            let name = request
+                .cookies()
+                .get_private(SESSION_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+                .or_else(|| {
+                    // Checking a password is slow on purpose, so only API
+                    // clients may send one with every request.
+                    if !api::is_api(request.uri().path()) {
+                        return None;
+                    }
+                    let (name, password) =
+                        basic_credentials(request.headers().get_one("Authorization")?)?;
+                    users.verify(&name, &password).map(|_| name)
+                });
+
+            // Look the user up on every request, so that removing a user from
+            // the users file ends their sessions.
+            CachedUser(name.and_then(|name| users.user(name)))
+        });
+        match cached.0 {
+            Some(ref user) => Outcome::Success(user.clone()),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The name and password of HTTP basic authentication, which lets the API be
+/// used without a session.
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+fn require_role(request: &Request, role: Role) -> request::Outcome<User, ()> {
+    let user = request.guard::<User>()?;
+    if user.role >= role {
+        Outcome::Success(user)
+    } else {
+        Outcome::Failure((Status::Forbidden, ()))
+    }
+}
+
+/// Request guard for routes that show the wiki.
+pub struct Viewer(pub User);
+
+/// Request guard for routes that change pages.
+pub struct Editor(pub User);
+
+/// Request guard for routes that can't be undone.
+pub struct Admin(pub User);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Viewer {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        require_role(request, Role::Viewer).map(Viewer)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Editor {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        require_role(request, Role::Editor).map(Editor)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        require_role(request, Role::Admin).map(Admin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn secret_key_is_kept_private() {
+        let dir = env::temp_dir().join(format!("simplewiki-secret-{}", process::id()));
+        let path = dir.join("keys/secret_key");
+
+        let key = load_secret_key(&path).unwrap();
+        assert_eq!(load_secret_key(&path).unwrap(), key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_users_file() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let content = format!("# comment\n\nalice:admin:{}\nbob:viewer:{}\n", hash, hash);
+        let users = Users::parse(&content).unwrap();
+
+        assert_eq!(users.verify("alice", "secret"), Some(Role::Admin));
+        assert_eq!(users.verify("bob", "secret"), Some(Role::Viewer));
+        assert_eq!(users.verify("alice", "wrong"), None);
+        assert_eq!(users.verify("carol", "secret"), None);
+    }
+
+    #[test]
+    fn parses_groups() {
+        let users = Users::parse("alice:editor:hash:hr, leads\nbob:viewer:hash:").unwrap();
+        assert_eq!(
+            users.user("alice".to_string()).unwrap().groups,
+            vec!["hr", "leads"]
+        );
+        assert!(users.user("bob".to_string()).unwrap().groups.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_users_file() {
+        assert!(Users::parse("alice:superuser:hash").is_err());
+        assert!(Users::parse("alice").is_err());
+        assert!(Users::parse(":admin:hash").is_err());
+    }
+
+    #[test]
+    fn parses_basic_credentials() {
+        let header = format!("Basic {}", base64::encode("alice:pass:word"));
+        assert_eq!(
+            basic_credentials(&header),
+            Some(("alice".to_string(), "pass:word".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer abc"), None);
+        assert_eq!(basic_credentials("Basic !!!"), None);
+    }
+
+    #[test]
+    fn roles_are_ordered() {
+        assert!(Role::Admin > Role::Editor);
+        assert!(Role::Editor > Role::Viewer);
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::atomic_file;
+use crate::errors::*;
+
+/// Who last edited each page, relative to the wiki root.
+const AUTHORS_FILE: &str = ".simplewiki/authors.json";
+
+/// Serializes the read-modify-write of the authors file.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LastEdit {
+    pub user: String,
+    pub time: DateTime<Utc>,
+}
+
+fn read(wiki_root: &Path) -> HashMap<String, LastEdit> {
+    fs::read_to_string(wiki_root.join(AUTHORS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Records `user` as the author of the latest edit of `page`.
+pub fn record(wiki_root: &Path, page: &str, user: &str) -> Result<()> {
+    let _lock = LOCK.lock().unwrap();
+
+    let mut authors = read(wiki_root);
+    authors.insert(
+        page.replace('\\', "/"),
+        LastEdit {
+            user: user.to_string(),
+            time: Utc::now(),
+        },
+    );
+
+    let content = serde_json::to_string_pretty(&authors).chain_err(|| "Failed to serialize")?;
+    atomic_file::write(&wiki_root.join(AUTHORS_FILE), content.as_bytes(), false)
+        .chain_err(|| "Failed to write authors file")
+}
+
+pub fn last_edit(wiki_root: &Path, page: &str) -> Option<LastEdit> {
+    read(wiki_root).remove(&page.replace('\\', "/"))
+}
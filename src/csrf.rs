@@ -0,0 +1,279 @@
+use rand::RngCore;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Cookie, Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Data, Outcome, State};
+
+/// Private cookie holding the CSRF token of the session.
+const TOKEN_COOKIE: &str = "csrf_token";
+
+/// Scripts send the token in this header instead of a form field.
+const TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// The `Host` headers the wiki answers to. Requests for any other host are
+/// rejected, so that another site can't reach the wiki through DNS rebinding.
+pub struct AllowedHosts {
+    /// `None` when any host name may be used.
+    hosts: Option<Vec<String>>,
+}
+
+impl AllowedHosts {
+    /// The names of the address the wiki is served on, and `extra` hosts
+    /// such as the one a reverse proxy forwards. A host without a port is
+    /// allowed on any port, and `*` allows any host. When serving on all
+    /// interfaces, any host is allowed unless `extra` hosts are given.
+    pub fn new(address: &str, port: u16, extra: &[String]) -> Self {
+        if extra.iter().any(|host| host == "*") {
+            return AllowedHosts { hosts: None };
+        }
+        let loopback = || {
+            vec![
+                format!("localhost:{}", port),
+                format!("127.0.0.1:{}", port),
+                format!("[::1]:{}", port),
+            ]
+        };
+        let hosts = match address {
+            "0.0.0.0" | "::" | "[::]" if extra.is_empty() => None,
+            "0.0.0.0" | "::" | "[::]" => Some(loopback()),
+            "localhost" | "127.0.0.1" | "::1" | "[::1]" => Some(loopback()),
+            address if address.contains(':') && !address.starts_with('[') => {
+                Some(vec![format!("[{}]:{}", address, port)])
+            }
+            address => Some(vec![format!("{}:{}", address, port)]),
+        };
+        let hosts = hosts.map(|mut hosts| {
+            hosts.extend(extra.iter().cloned());
+            hosts
+        });
+        AllowedHosts { hosts }
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        match self.hosts {
+            Some(ref hosts) => hosts.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(host)
+                    || (without_port(allowed) == allowed
+                        && without_port(host).eq_ignore_ascii_case(allowed))
+            }),
+            None => true,
+        }
+    }
+}
+
+/// `localhost:8000` is `localhost`, and `[::1]:8000` is `[::1]`.
+fn without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i)
+            if host[i + 1..].chars().all(|c| c.is_ascii_digit())
+                && (!host.starts_with('[') || host[..i].ends_with(']')) =>
+        {
+            &host[..i]
+        }
+        _ => host,
+    }
+}
+
+/// `http://localhost:8000/page` has the host `localhost:8000`.
+fn url_host(url: &str) -> Option<&str> {
+    let rest = &url[url.find("://")? + 3..];
+    Some(rest.split('/').next().unwrap_or(rest))
+}
+
+/// Checks the `Host` header.
+fn check_host<'r>(request: &'r Request) -> request::Outcome<&'r str, ()> {
+    let hosts = request.guard::<State<AllowedHosts>>()?;
+    match request.headers().get_one("Host") {
+        Some(host) if hosts.allows(host) => Outcome::Success(host),
+        _ => Outcome::Failure((Status::Forbidden, ())),
+    }
+}
+
+/// Requests for hosts that aren't allowed are sent here instead of to the
+/// route they asked for.
+pub const FORBIDDEN_HOST_URI: &str = "/_/forbidden-host";
+
+/// Checks the `Host` header of every request, before any route runs.
+pub struct HostCheck;
+
+impl Fairing for HostCheck {
+    fn info(&self) -> Info {
+        Info {
+            name: "Host check",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if check_host(request).is_success() {
+            return;
+        }
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(FORBIDDEN_HOST_URI).unwrap());
+    }
+}
+
+/// Checks the `Host` header, and that the `Origin` header, or the `Referer`
+/// header when there is no origin, points at the same host. Only requests
+/// that change something are checked, since links from other sites are fine.
+fn check_origin(request: &Request) -> request::Outcome<(), ()> {
+    let host = check_host(request)?;
+    let headers = request.headers();
+
+    let origin = headers
+        .get_one("Origin")
+        .or_else(|| headers.get_one("Referer"));
+    match origin {
+        Some(origin) if url_host(origin).map_or(true, |o| !o.eq_ignore_ascii_case(host)) => {
+            Outcome::Failure((Status::Forbidden, ()))
+        }
+        _ => Outcome::Success(()),
+    }
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compares in constant time, so that the token can't be guessed byte by byte.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// Request guard for pages with forms. Gives the token of the session, which
+/// is created on the first request.
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let mut cookies = request.cookies();
+        if let Some(cookie) = cookies.get_private(TOKEN_COOKIE) {
+            return Outcome::Success(CsrfToken(cookie.value().to_string()));
+        }
+
+        let token = new_token();
+        cookies.add_private(Cookie::new(TOKEN_COOKIE, token.clone()));
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+/// Request guard for routes that change something. Rejects requests from
+/// other sites, and keeps the token to check the submitted one against.
+pub struct CsrfCheck {
+    token: Option<String>,
+    header: Option<String>,
+}
+
+impl CsrfCheck {
+    /// Checks the token submitted with a form, or else the one in the
+    /// `X-CSRF-Token` header.
+    pub fn verify(&self, submitted: Option<&str>) -> bool {
+        match (self.token.as_ref(), submitted.or(self.header.as_deref())) {
+            (Some(token), Some(submitted)) => tokens_match(token, submitted),
+            _ => false,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfCheck {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        check_origin(request)?;
+
+        Outcome::Success(CsrfCheck {
+            token: request
+                .cookies()
+                .get_private(TOKEN_COOKIE)
+                .map(|cookie| cookie.value().to_string()),
+            header: request.headers().get_one(TOKEN_HEADER).map(String::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_names_are_interchangeable() {
+        let hosts = AllowedHosts::new("localhost", 8000, &[]);
+        assert!(hosts.allows("localhost:8000"));
+        assert!(hosts.allows("127.0.0.1:8000"));
+        assert!(!hosts.allows("localhost:8001"));
+        assert!(!hosts.allows("evil.example:8000"));
+
+        assert!(AllowedHosts::new("0.0.0.0", 80, &[]).allows("wiki.example:80"));
+        assert!(AllowedHosts::new("fe80::1", 80, &[]).allows("[fe80::1]:80"));
+    }
+
+    #[test]
+    fn configured_hosts() {
+        let hosts = AllowedHosts::new(
+            "localhost",
+            8000,
+            &["wiki.example".to_string(), "[::2]:443".to_string()],
+        );
+        assert!(hosts.allows("localhost:8000"));
+        assert!(hosts.allows("wiki.example"));
+        assert!(hosts.allows("WIKI.example:443"));
+        assert!(hosts.allows("[::2]:443"));
+        assert!(!hosts.allows("[::2]:80"));
+        assert!(!hosts.allows("evil.example"));
+
+        assert!(AllowedHosts::new("localhost", 8000, &["*".to_string()]).allows("any.example"));
+
+        let hosts = AllowedHosts::new("0.0.0.0", 80, &["wiki.example".to_string()]);
+        assert!(hosts.allows("wiki.example"));
+        assert!(hosts.allows("localhost:80"));
+        assert!(!hosts.allows("evil.example"));
+    }
+
+    #[test]
+    fn origin_hosts() {
+        assert_eq!(url_host("http://localhost:8000"), Some("localhost:8000"));
+        assert_eq!(
+            url_host("http://localhost:8000/a/b?c"),
+            Some("localhost:8000")
+        );
+        assert_eq!(url_host("null"), None);
+    }
+
+    #[test]
+    fn submitted_token_or_header_must_match() {
+        let check = CsrfCheck {
+            token: Some("abc".to_string()),
+            header: None,
+        };
+        assert!(check.verify(Some("abc")));
+        assert!(!check.verify(Some("abd")));
+        assert!(!check.verify(None));
+
+        let check = CsrfCheck {
+            token: Some("abc".to_string()),
+            header: Some("abc".to_string()),
+        };
+        assert!(check.verify(None));
+
+        let check = CsrfCheck {
+            token: None,
+            header: None,
+        };
+        assert!(!check.verify(Some("")));
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Used when no `--editor` is given.
+pub const DEFAULT_EDITOR: &str = "subl {file}:{line}:{column}";
+
+/// A command line used to open a page in an external editor, such as
+/// `code -g {file}:{line}:{column}`. The `{file}`, `{line}` and `{column}`
+/// placeholders are replaced when the editor is launched. If the command has
+/// no `{file}` placeholder, the file is passed as the last argument.
+pub struct EditorCommand {
+    args: Vec<String>,
+}
+
+impl EditorCommand {
+    pub fn new(template: &str) -> Self {
+        let mut args = split_args(template);
+        if !args.iter().any(|arg| arg.contains("{file}")) {
+            args.push("{file}".to_string());
+        }
+        EditorCommand { args }
+    }
+
+    /// The program and its arguments, with all placeholders replaced.
+    pub fn args(&self, file: &Path, line: usize, column: usize) -> Vec<String> {
+        let file = file.to_string_lossy();
+        self.args
+            .iter()
+            .map(|arg| {
+                arg.replace("{file}", &file)
+                    .replace("{line}", &line.to_string())
+                    .replace("{column}", &column.to_string())
+            })
+            .collect()
+    }
+
+    /// Launches the editor without waiting for it to exit.
+    pub fn spawn(&self, file: &Path, line: usize, column: usize) -> io::Result<()> {
+        let args = self.args(file, line, column);
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No editor configured"))?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Reap the editor when it exits, so it doesn't linger as a zombie.
+        thread::spawn(move || child.wait());
+
+        Ok(())
+    }
+}
+
+/// Splits a command line on whitespace. Single and double quotes can be used
+/// for arguments containing spaces.
+fn split_args(command: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote: Option<char> = None;
+
+    for c in command.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            None if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced() {
+        let editor = EditorCommand::new("code -g {file}:{line}:{column}");
+        assert_eq!(
+            editor.args(Path::new("wiki/a.md"), 12, 3),
+            vec!["code", "-g", "wiki/a.md:12:3"]
+        );
+    }
+
+    #[test]
+    fn file_is_appended_without_placeholder() {
+        let editor = EditorCommand::new("subl");
+        assert_eq!(editor.args(Path::new("a.md"), 1, 1), vec!["subl", "a.md"]);
+    }
+
+    #[test]
+    fn quoted_arguments_are_kept_together() {
+        let editor = EditorCommand::new(r#""C:\Program Files\Editor\ed.exe" '+{line}' {file}"#);
+        assert_eq!(
+            editor.args(Path::new("a b.md"), 4, 1),
+            vec![r"C:\Program Files\Editor\ed.exe", "+4", "a b.md"]
+        );
+    }
+}
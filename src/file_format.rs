@@ -0,0 +1,188 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Anything that isn't valid UTF-8 is read as Latin-1, since every byte
+    /// sequence is valid Latin-1.
+    Latin1,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+/// How a page is stored on disk, so that saving it writes it back the same
+/// way it was read.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub trailing_newline: bool,
+}
+
+impl Default for FileFormat {
+    /// Used for new pages.
+    fn default() -> Self {
+        FileFormat {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            trailing_newline: true,
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+fn decode_utf16(bytes: &[u8], to_u16: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| to_u16([pair[0], *pair.get(1).unwrap_or(&0)]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Decodes the content of a file and detects its format.
+pub fn decode(bytes: &[u8]) -> (String, FileFormat) {
+    let (text, encoding) = if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        (
+            String::from_utf8_lossy(rest).into_owned(),
+            Encoding::Utf8Bom,
+        )
+    } else if let Some(rest) = bytes.strip_prefix(UTF16_LE_BOM) {
+        (decode_utf16(rest, u16::from_le_bytes), Encoding::Utf16Le)
+    } else if let Some(rest) = bytes.strip_prefix(UTF16_BE_BOM) {
+        (decode_utf16(rest, u16::from_be_bytes), Encoding::Utf16Be)
+    } else {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), Encoding::Utf8),
+            Err(_) => (bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1),
+        }
+    };
+
+    let crlf_count = text.matches("\r\n").count();
+    let lf_count = text.matches('\n').count();
+    let line_ending = if crlf_count > 0 && crlf_count * 2 >= lf_count {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+
+    let format = FileFormat {
+        encoding,
+        line_ending,
+        trailing_newline: text.is_empty() || text.ends_with('\n'),
+    };
+
+    (text, format)
+}
+
+/// Encodes `text` in the given format. Line endings are normalized, since
+/// browsers always submit text with CRLF line endings.
+///
+/// Text that can't be represented in Latin-1 is saved as UTF-8 rather than
+/// losing characters.
+pub fn encode(text: &str, format: &FileFormat) -> Vec<u8> {
+    let mut text = text.replace("\r\n", "\n");
+    if format.trailing_newline {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    } else {
+        let trimmed_len = text.trim_end_matches('\n').len();
+        text.truncate(trimmed_len);
+    }
+
+    if format.line_ending == LineEnding::CrLf {
+        text = text.replace('\n', "\r\n");
+    }
+
+    match format.encoding {
+        Encoding::Utf8 => text.into_bytes(),
+        Encoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+        Encoding::Utf16Le => {
+            let mut bytes = UTF16_LE_BOM.to_vec();
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            bytes
+        }
+        Encoding::Utf16Be => {
+            let mut bytes = UTF16_BE_BOM.to_vec();
+            bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            bytes
+        }
+        Encoding::Latin1 => {
+            if text.chars().all(|c| (c as u32) < 256) {
+                text.chars().map(|c| c as u8).collect()
+            } else {
+                text.into_bytes()
+            }
+        }
+    }
+}
+
+/// Reads and decodes a text file.
+pub fn read_text(path: &Path) -> io::Result<(String, FileFormat)> {
+    let bytes = fs::read(path)?;
+    Ok(decode(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        let (text, format) = decode(bytes);
+        encode(&text, &format)
+    }
+
+    #[test]
+    fn detects_encodings() {
+        assert_eq!(decode(b"abc").1.encoding, Encoding::Utf8);
+        assert_eq!(decode(b"\xEF\xBB\xBFabc").1.encoding, Encoding::Utf8Bom);
+        assert_eq!(decode(b"\xFF\xFEa\x00").1.encoding, Encoding::Utf16Le);
+        assert_eq!(decode(b"\xFE\xFF\x00a").1.encoding, Encoding::Utf16Be);
+        assert_eq!(decode(b"bl\xE5b\xE6r").0, "blåbær");
+        assert_eq!(decode(b"bl\xE5b\xE6r").1.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let files: Vec<&[u8]> = vec![
+            b"# Title\n\ntext\n",
+            b"# Title\r\n\r\ntext\r\n",
+            b"no trailing newline",
+            b"\xEF\xBB\xBF# BOM\r\n",
+            b"\xFF\xFE#\x00 \x00\xE5\x00\r\x00\n\x00",
+            b"\xFE\xFF\x00#\x00 \x00\xE5\x00\n",
+            b"Latin-1 \xE6\xF8\xE5\r\n",
+            b"",
+        ];
+        for file in files {
+            assert_eq!(round_trip(file), file.to_vec());
+        }
+    }
+
+    #[test]
+    fn browser_text_gets_file_line_endings() {
+        let (_, format) = decode(b"a\r\nb\r\n");
+        assert_eq!(encode("a\r\nb\r\nc", &format), b"a\r\nb\r\nc\r\n".to_vec());
+
+        let (_, format) = decode(b"a\nb");
+        assert_eq!(encode("a\r\nb\r\nc\r\n", &format), b"a\nb\nc".to_vec());
+    }
+
+    #[test]
+    fn latin1_falls_back_to_utf8() {
+        let (_, format) = decode(b"\xE6\n");
+        assert_eq!(encode("€\n", &format), "€\n".as_bytes().to_vec());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use walkdir::WalkDir;
+
+use crate::atomic_file;
+use crate::errors::*;
+use crate::file_format;
+use crate::search;
+use crate::section;
+
+/// The search index is stored in this folder, relative to the wiki root.
+pub const INDEX_DIR: &str = ".simplewiki/index";
+const INDEX_FILE: &str = "index.json";
+
+/// Changes to the index are saved this often. Changes that weren't saved
+/// when the wiki stops are picked up from the files when it starts again.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bumped whenever the index format or the tokenizer changes, so that old
+/// indexes are rebuilt instead of read.
+const INDEX_VERSION: u32 = 2;
+
+/// A term in the title of a page counts as much as this many terms in the
+/// body.
+const TITLE_WEIGHT: f32 = 5.0;
+const HEADING_WEIGHT: f32 = 3.0;
+const TAG_WEIGHT: f32 = 3.0;
+const BODY_WEIGHT: f32 = 1.0;
+
+/// BM25 parameters.
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Document {
+    pub title: String,
+    /// The `title` in the front matter of the page, if it has one.
+    pub front_matter_title: Option<String>,
+    pub headings: Vec<String>,
+    pub tags: Vec<String>,
+    /// Modification time of the file, in milliseconds since the epoch. Used
+    /// to find the pages that changed while the wiki wasn't running.
+    pub modified: u64,
+    /// Sum of the weighted frequencies of all terms.
+    length: f32,
+    terms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct IndexData {
+    version: u32,
+    /// Pages, such as `folder/page`, and what was indexed from them.
+    documents: HashMap<String, Document>,
+    /// For every term, the pages containing it, with its weighted frequency.
+    postings: HashMap<String, HashMap<String, f32>>,
+}
+
+/// Splits text into lowercase words.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+}
+
+/// Splits a page into its front matter, a block of `key: value` lines
+/// between two `---` lines at the very start, and the rest of the page.
+pub fn split_front_matter(content: &str) -> (Option<&str>, &str) {
+    let rest = match content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+    {
+        Some(rest) => rest,
+        None => return (None, content),
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" || line.trim_end() == "..." {
+            return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+    (None, content)
+}
+
+/// The `title` in the front matter of a page.
+pub fn front_matter_title(content: &str) -> Option<String> {
+    let (front_matter, _) = split_front_matter(content);
+    front_matter?
+        .lines()
+        .filter_map(|line| line.strip_prefix("title:"))
+        .map(|title| title.trim().trim_matches(|c| c == '"' || c == '\''))
+        .find(|title| !title.is_empty())
+        .map(str::to_string)
+}
+
+/// Finds `#tags` in a page, outside of code blocks. Headings aren't tags,
+/// since they have a space after the `#`.
+pub fn tags(content: &str) -> Vec<String> {
+    let mut tags = vec![];
+    let mut in_code_block = false;
+
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+
+        for word in line.split_whitespace() {
+            let tag: String = match word.strip_prefix('#') {
+                Some(rest) if rest.starts_with(char::is_alphabetic) => rest
+                    .chars()
+                    .take_while(|&c| c.is_alphanumeric() || c == '-' || c == '_' || c == '/')
+                    .collect(),
+                _ => continue,
+            };
+            tags.push(tag.trim_end_matches('/').to_lowercase());
+        }
+    }
+
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// `folder/page.md` is the page `folder/page`. Files that aren't markdown,
+/// and files in hidden folders, aren't pages.
+pub fn page_name(relative_path: &Path) -> Option<String> {
+    if relative_path.extension().map_or(true, |ext| ext != "md") {
+        return None;
+    }
+
+    let mut parts = vec![];
+    for component in relative_path.with_extension("").components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part.to_str()?;
+                if part.starts_with('.') {
+                    return None;
+                }
+                parts.push(part.to_string());
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(parts.join("/"))
+}
+
+pub fn page_file(wiki_root: &Path, page: &str) -> PathBuf {
+    wiki_root.join(format!("{}.md", page))
+}
+
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+impl IndexData {
+    fn remove(&mut self, page: &str) -> bool {
+        let document = match self.documents.remove(page) {
+            Some(document) => document,
+            None => return false,
+        };
+
+        for term in document.terms {
+            if let Some(pages) = self.postings.get_mut(&term) {
+                pages.remove(page);
+                if pages.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+        true
+    }
+
+    fn add(&mut self, page: &str, content: &str, modified: u64) {
+        self.remove(page);
+
+        let title = page.rsplit('/').next().unwrap_or(page).to_string();
+        let front_matter_title = front_matter_title(content);
+        let (_, body) = split_front_matter(content);
+        let headings: Vec<String> = section::sections(body)
+            .into_iter()
+            .map(|section| section.title)
+            .collect();
+        let tags = tags(content);
+
+        let mut frequencies: HashMap<String, f32> = HashMap::new();
+        let mut add_terms = |text: &str, weight: f32| {
+            for term in tokenize(text) {
+                *frequencies.entry(term).or_insert(0.0) += weight;
+            }
+        };
+        add_terms(&title, TITLE_WEIGHT);
+        if let Some(front_matter_title) = &front_matter_title {
+            add_terms(front_matter_title, TITLE_WEIGHT);
+        }
+        for heading in &headings {
+            add_terms(heading, HEADING_WEIGHT);
+        }
+        for tag in &tags {
+            add_terms(tag, TAG_WEIGHT);
+        }
+        add_terms(content, BODY_WEIGHT);
+
+        for (term, frequency) in &frequencies {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(page.to_string(), *frequency);
+        }
+
+        let document = Document {
+            title,
+            front_matter_title,
+            headings,
+            tags,
+            modified,
+            length: frequencies.values().sum(),
+            terms: frequencies.into_keys().collect(),
+        };
+        self.documents.insert(page.to_string(), document);
+    }
+
+    /// Scores pages with BM25. A word matches the terms that contain it, but
+    /// a term that is exactly the word scores higher. The score of a page is
+    /// the sum of the best score of every word.
+    fn rank(&self, words: &[String], require_all: bool) -> Vec<(String, f32)> {
+        let count = self.documents.len() as f32;
+        let average_length =
+            self.documents.values().map(|d| d.length).sum::<f32>() / count.max(1.0);
+
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        let mut matched: HashMap<&str, usize> = HashMap::new();
+
+        let mut words: Vec<&String> = words.iter().collect();
+        words.sort();
+        words.dedup();
+
+        for word in &words {
+            let mut best: HashMap<&str, f32> = HashMap::new();
+            for (term, pages) in &self.postings {
+                if !term.contains(word.as_str()) {
+                    continue;
+                }
+
+                let df = pages.len() as f32;
+                let idf = (1.0 + (count - df + 0.5) / (df + 0.5)).ln();
+                let partial = if term == *word { 1.0 } else { 0.5 };
+
+                for (page, &tf) in pages {
+                    let length = self.documents.get(page).map_or(0.0, |d| d.length);
+                    let norm = K1 * (1.0 - B + B * length / average_length.max(1.0));
+                    let score = partial * idf * tf * (K1 + 1.0) / (tf + norm);
+                    let best = best.entry(page.as_str()).or_insert(0.0);
+                    *best = best.max(score);
+                }
+            }
+
+            for (page, score) in best {
+                *scores.entry(page).or_insert(0.0) += score;
+                *matched.entry(page).or_insert(0) += 1;
+            }
+        }
+
+        let mut ranked: Vec<(String, f32)> = if require_all {
+            scores
+                .into_iter()
+                .filter(|(page, _)| matched.get(page) == Some(&words.len()))
+                .map(|(page, score)| (page.to_string(), score))
+                .collect()
+        } else {
+            self.documents
+                .keys()
+                .map(|page| (page.clone(), *scores.get(page.as_str()).unwrap_or(&0.0)))
+                .collect()
+        };
+
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+/// A persistent inverted index of the titles, headings, tags and bodies of
+/// all pages. It is brought up to date when the wiki starts, and kept up to
+/// date from the file watcher.
+pub struct SearchIndex {
+    wiki_root: PathBuf,
+    data: RwLock<IndexData>,
+    /// Whether the index changed since it was saved.
+    dirty: AtomicBool,
+}
+
+impl SearchIndex {
+    /// Loads the index, and updates it with the pages that changed since it
+    /// was saved.
+    pub fn open(wiki_root: &Path) -> Result<Self> {
+        let path = wiki_root.join(INDEX_DIR).join(INDEX_FILE);
+        let data = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<IndexData>(&content).ok())
+            .filter(|data| data.version == INDEX_VERSION)
+            .unwrap_or_else(|| IndexData {
+                version: INDEX_VERSION,
+                ..IndexData::default()
+            });
+
+        let index = SearchIndex {
+            wiki_root: wiki_root.to_path_buf(),
+            data: RwLock::new(data),
+            dirty: AtomicBool::new(false),
+        };
+        index.update_all()?;
+        index.save()?;
+        Ok(index)
+    }
+
+    /// Saves the index if it changed. Searches go on while it is saved.
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let content = serde_json::to_string(&*self.data.read().unwrap());
+        let path = self.wiki_root.join(INDEX_DIR).join(INDEX_FILE);
+        let saved = content
+            .chain_err(|| "Failed to serialize index")
+            .and_then(|content| {
+                atomic_file::write(&path, content.as_bytes(), false)
+                    .chain_err(|| "Failed to save index")
+            });
+        if saved.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        saved
+    }
+
+    /// Saves the index from a background thread whenever it changed.
+    pub fn save_periodically(index: &Arc<SearchIndex>) {
+        let index = index.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_INTERVAL);
+            if let Err(e) = index.save() {
+                println!("Failed to save search index: {}", e);
+            }
+        });
+    }
+
+    /// Indexes new and changed pages, and removes deleted ones.
+    pub fn update_all(&self) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        let mut changed = false;
+        let mut seen = HashSet::new();
+
+        let walker = WalkDir::new(&self.wiki_root).into_iter();
+        for entry in walker.filter_entry(search::is_markdown) {
+            let entry = entry.chain_err(|| "Failed to read wiki folder")?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+
+            let relative_path = entry.path().strip_prefix(&self.wiki_root).unwrap();
+            let page = match page_name(relative_path) {
+                Some(page) => page,
+                None => continue,
+            };
+
+            let modified = modified(entry.path());
+            let unchanged = data
+                .documents
+                .get(&page)
+                .map_or(false, |document| document.modified == modified);
+
+            if !unchanged {
+                if let Ok((content, _)) = file_format::read_text(entry.path()) {
+                    data.add(&page, &content, modified);
+                    changed = true;
+                }
+            }
+            seen.insert(page);
+        }
+
+        let removed: Vec<String> = data
+            .documents
+            .keys()
+            .filter(|page| !seen.contains(*page))
+            .cloned()
+            .collect();
+        for page in removed {
+            changed |= data.remove(&page);
+        }
+
+        if changed {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Updates the index after a file or folder in the wiki has changed.
+    pub fn update_path(&self, path: &Path) -> Result<()> {
+        let relative_path = match path.strip_prefix(&self.wiki_root) {
+            Ok(relative_path) => relative_path.to_path_buf(),
+            Err(_) => {
+                let root =
+                    fs::canonicalize(&self.wiki_root).chain_err(|| "Failed to find wiki folder")?;
+                match path.strip_prefix(root) {
+                    Ok(relative_path) => relative_path.to_path_buf(),
+                    Err(_) => return Ok(()),
+                }
+            }
+        };
+
+        let is_hidden = relative_path.components().any(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .map_or(false, |part| part.starts_with('.') && part != ".")
+        });
+        if is_hidden {
+            return Ok(());
+        }
+
+        match page_name(&relative_path) {
+            Some(page) => {
+                let mut data = self.data.write().unwrap();
+                match file_format::read_text(path) {
+                    Ok((content, _)) => data.add(&page, &content, modified(path)),
+                    Err(_) => {
+                        data.remove(&page);
+                    }
+                }
+                self.dirty.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            // A folder was created, moved or deleted.
+            None if path.is_dir() || path.extension().is_none() => self.update_all(),
+            None => Ok(()),
+        }
+    }
+
+    /// Pages ranked by how well they match `words`, best first. With
+    /// `require_all`, only the pages containing every word are returned.
+    /// Otherwise every page is returned.
+    pub fn rank(&self, words: &[String], require_all: bool) -> Vec<(String, f32)> {
+        self.data.read().unwrap().rank(words, require_all)
+    }
+
+    pub fn document(&self, page: &str) -> Option<Document> {
+        self.data.read().unwrap().documents.get(page).cloned()
+    }
+
+    /// Every page, with the title in its front matter, or else its first
+    /// heading.
+    pub fn titles(&self) -> Vec<(String, Option<String>)> {
+        self.data
+            .read()
+            .unwrap()
+            .documents
+            .iter()
+            .map(|(page, document)| {
+                let title = document
+                    .front_matter_title
+                    .as_ref()
+                    .or_else(|| document.headings.first());
+                (page.clone(), title.cloned())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn words(query: &str) -> Vec<String> {
+        tokenize(query).collect()
+    }
+
+    #[test]
+    fn finds_tags() {
+        let content = "# Heading\nSome #Rust and #web-dev, not a#tag.\n```\n#include\n```\n##x";
+        assert_eq!(tags(content), vec!["rust", "web-dev"]);
+    }
+
+    #[test]
+    fn finds_front_matter_titles() {
+        let content = "---\ntitle: \"Release plan\"\ntags: [a]\n---\n# Intro\n";
+        assert_eq!(
+            front_matter_title(content),
+            Some("Release plan".to_string())
+        );
+        assert_eq!(split_front_matter(content).1, "# Intro\n");
+        assert_eq!(front_matter_title("# Intro\n---\ntitle: x\n---\n"), None);
+        assert_eq!(front_matter_title("---\ntitle: unclosed\n"), None);
+
+        let mut data = IndexData::default();
+        data.add("notes/plan", content, 1);
+        assert_eq!(data.documents["notes/plan"].headings, vec!["Intro"]);
+        let ranked = data.rank(&words("release"), true);
+        assert_eq!(ranked[0].0, "notes/plan");
+    }
+
+    #[test]
+    fn page_names() {
+        assert_eq!(page_name(Path::new("a/b.md")), Some("a/b".to_string()));
+        assert_eq!(page_name(Path::new("a/b.png")), None);
+        assert_eq!(page_name(Path::new(".simplewiki/trash/x.md")), None);
+    }
+
+    #[test]
+    fn title_matches_rank_first() {
+        let mut data = IndexData::default();
+        data.add("notes/rust", "# Intro\nSome text", 1);
+        data.add("other", "This mentions rust once.", 1);
+        data.add("unrelated", "Nothing here", 1);
+
+        let ranked = data.rank(&words("rust"), true);
+        let pages: Vec<&str> = ranked.iter().map(|(page, _)| page.as_str()).collect();
+        assert_eq!(pages, vec!["notes/rust", "other"]);
+
+        // Every word must be found, but words can be a part of a term.
+        assert_eq!(data.rank(&words("rus intro"), true).len(), 1);
+        assert_eq!(data.rank(&words("rust missing"), true).len(), 0);
+        assert_eq!(data.rank(&words("rust missing"), false).len(), 3);
+    }
+
+    #[test]
+    fn removed_pages_leave_no_terms() {
+        let mut data = IndexData::default();
+        data.add("a", "alpha beta", 1);
+        data.add("a", "gamma", 2);
+        assert!(!data.postings.contains_key("alpha"));
+        assert!(data.remove("a"));
+        assert!(data.postings.is_empty());
+    }
+
+    #[test]
+    fn index_is_saved_and_updated() {
+        let root = env::temp_dir().join(format!("simplewiki-index-{}", process::id()));
+        fs::create_dir_all(root.join("folder")).unwrap();
+        fs::write(root.join("folder/page.md"), "hello #world").unwrap();
+
+        let index = SearchIndex::open(&root).unwrap();
+        assert_eq!(index.rank(&words("hello"), true).len(), 1);
+        assert_eq!(index.document("folder/page").unwrap().tags, vec!["world"]);
+
+        fs::write(root.join("new.md"), "hello again").unwrap();
+        index.update_path(&root.join("new.md")).unwrap();
+        fs::remove_file(root.join("folder/page.md")).unwrap();
+        index.update_path(&root.join("folder/page.md")).unwrap();
+
+        // Changes are only written when the index is saved.
+        let saved = root.join(INDEX_DIR).join(INDEX_FILE);
+        assert!(!fs::read_to_string(&saved).unwrap().contains("again"));
+        index.save().unwrap();
+        assert!(fs::read_to_string(&saved).unwrap().contains("again"));
+
+        let reopened = SearchIndex::open(&root).unwrap();
+        let ranked = reopened.rank(&words("hello"), true);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, "new");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::path::Path;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use rocket::http::uri::Uri;
+use rocket::http::RawStr;
+use walkdir::WalkDir;
+
+use crate::file_format;
+use crate::search::is_markdown;
+
+/// Returns the destination of every link in a markdown document.
+pub fn links_in(content: &str) -> Vec<String> {
+    Parser::new_ext(content, Options::all())
+        .filter_map(|event| match event {
+            Event::Start(Tag::Link(_, dest, _)) => Some(dest.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_external(dest: &str) -> bool {
+    dest.contains("://") || dest.starts_with("mailto:")
+}
+
+/// Joins `dest` onto the folder of `source_page`, the same way the browser
+/// would, and returns the path without a leading slash.
+fn join_path(source_page: &str, dest: &str) -> String {
+    let mut parts: Vec<&str> = vec![];
+    if !dest.starts_with('/') {
+        let source_page = source_page.trim_start_matches('/');
+        parts.extend(source_page.split('/'));
+        // Relative links are relative to the folder of the page.
+        parts.pop();
+    }
+
+    for part in dest.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    parts.join("/")
+}
+
+/// Resolves a link found on `source_page` to the page it points to. Returns
+/// `None` for external links and anchors.
+pub fn resolve_link(source_page: &str, dest: &str) -> Option<String> {
+    let dest = dest.split(|c| c == '#' || c == '?').next().unwrap_or("");
+    if dest.is_empty() || is_external(dest) {
+        return None;
+    }
+
+    let dest = RawStr::from_str(dest).percent_decode_lossy();
+
+    let page = join_path(source_page, &dest);
+    let page = page.trim_end_matches(".md");
+    if page.is_empty() {
+        None
+    } else {
+        Some(page.to_string())
+    }
+}
+
+/// Rewrites a link found on `source_page` to an absolute url, so that it
+/// works when the page is rendered somewhere else, such as in the editor.
+pub fn absolute_url(source_page: &str, dest: &str) -> String {
+    let split_at = dest.find(|c| c == '#' || c == '?').unwrap_or(dest.len());
+    let (path, suffix) = dest.split_at(split_at);
+    if path.is_empty() || is_external(path) {
+        return dest.to_string();
+    }
+
+    format!("/{}{}", join_path(source_page, path), suffix)
+}
+
+/// The path of the url of `page`, without the leading slash. Every folder
+/// and the page name are percent-encoded on their own.
+pub fn page_url_path(page: &str) -> String {
+    page.split('/')
+        .map(|part| Uri::percent_encode(part).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Finds every page in the wiki that links to `page`.
+pub fn find_backlinks(wiki_root: &Path, page: &str) -> Vec<String> {
+    let page = page.replace('\\', "/");
+    let mut backlinks = vec![];
+
+    let walker = WalkDir::new(wiki_root).into_iter();
+    for entry in walker.filter_entry(is_markdown).filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let source_page = match entry.path().strip_prefix(wiki_root) {
+            Ok(path) => path.to_string_lossy().replace('\\', "/"),
+            Err(_) => continue,
+        };
+        let source_page = source_page.trim_end_matches(".md");
+        if source_page == page {
+            continue;
+        }
+
+        let content = match file_format::read_text(entry.path()) {
+            Ok((content, _)) => content,
+            Err(_) => continue,
+        };
+
+        let links_here = links_in(&content)
+            .iter()
+            .filter_map(|dest| resolve_link(source_page, dest))
+            .any(|target| target == page);
+
+        if links_here {
+            backlinks.push(source_page.to_string());
+        }
+    }
+
+    backlinks.sort();
+    backlinks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_relative_links() {
+        assert_eq!(resolve_link("a/b", "c"), Some("a/c".into()));
+        assert_eq!(resolve_link("a/b", "c.md"), Some("a/c".into()));
+        assert_eq!(resolve_link("a/b", "../c"), Some("c".into()));
+        assert_eq!(resolve_link("a/b", "./d/e#section"), Some("a/d/e".into()));
+        assert_eq!(resolve_link("b", "c"), Some("c".into()));
+        assert_eq!(resolve_link("a/b", "my%20page"), Some("a/my page".into()));
+    }
+
+    #[test]
+    fn resolves_absolute_links() {
+        assert_eq!(resolve_link("a/b", "/c/d"), Some("c/d".into()));
+        assert_eq!(resolve_link("a/b", "/"), None);
+    }
+
+    #[test]
+    fn makes_links_absolute() {
+        assert_eq!(absolute_url("a/b", "c.png"), "/a/c.png");
+        assert_eq!(absolute_url("a/b", "../c#top"), "/c#top");
+        assert_eq!(absolute_url("a/b", "/d"), "/d");
+        assert_eq!(absolute_url("a/b", "#top"), "#top");
+        assert_eq!(absolute_url("a/b", "http://x.org/y"), "http://x.org/y");
+    }
+
+    #[test]
+    fn encodes_page_urls() {
+        assert_eq!(page_url_path("a/b"), "a/b");
+        assert_eq!(
+            page_url_path("my notes/50% #1?"),
+            "my%20notes/50%25%20%231%3F"
+        );
+    }
+
+    #[test]
+    fn ignores_external_links() {
+        assert_eq!(resolve_link("a/b", "https://example.com/a/c"), None);
+        assert_eq!(resolve_link("a/b", "mailto:me@example.com"), None);
+        assert_eq!(resolve_link("a/b", "#heading"), None);
+    }
+
+    #[test]
+    fn finds_links_in_markdown() {
+        let links = links_in("See [c](c) and [d](/d/e \"title\").\n\n[ref]: f");
+        assert_eq!(links, vec!["c".to_string(), "/d/e".to_string()]);
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::http::ContentType;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::Outcome;
+
+use crate::acl::Access;
+use crate::auth::User;
+use crate::index;
+use crate::watcher::{self, Change};
+
+/// Sent to the browsers as server-sent events, with JSON data such as
+/// `{"type":"page_changed","page":"folder/page"}`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PageChanged {
+        page: String,
+    },
+    PageCreated {
+        page: String,
+    },
+    PageDeleted {
+        page: String,
+    },
+    PageRenamed {
+        from: String,
+        to: String,
+    },
+    /// A page or folder was added, moved or removed, so the list of pages in
+    /// the navbar changed.
+    TreeChanged {
+        path: String,
+    },
+    /// Events were lost, so anything may have changed.
+    Rescan,
+}
+
+impl Event {
+    /// Whether a browser showing `page` gets this event. Events about other
+    /// pages are only sent to the browsers showing them.
+    fn concerns(&self, page: Option<&str>) -> bool {
+        match self {
+            Event::PageChanged { page: changed }
+            | Event::PageCreated { page: changed }
+            | Event::PageDeleted { page: changed } => page == Some(changed.as_str()),
+            Event::PageRenamed { from, to } => {
+                page == Some(from.as_str()) || page == Some(to.as_str())
+            }
+            Event::TreeChanged { .. } | Event::Rescan => true,
+        }
+    }
+}
+
+/// The page a file in an `.attachments` folder, or the folder itself,
+/// belongs to.
+fn attachment_page(relative_path: &Path) -> Option<String> {
+    let folder = if relative_path
+        .extension()
+        .map_or(false, |ext| ext == "attachments")
+    {
+        relative_path
+    } else if watcher::is_attachment(relative_path) {
+        relative_path.parent()?
+    } else {
+        return None;
+    };
+    index::page_name(&folder.with_extension("md"))
+}
+
+/// What a change to the wiki folder means to the browsers. `known` tells
+/// whether a page was in the wiki before the change, since saving a page
+/// through a temporary file shows up as creating it.
+pub fn events(wiki_root: &Path, change: &Change, known: &dyn Fn(&str) -> bool) -> Vec<Event> {
+    let page = |path: &Path| {
+        watcher::relative_path(wiki_root, path).and_then(|path| index::page_name(&path))
+    };
+    let attachment = |path: &Path| {
+        watcher::relative_path(wiki_root, path)
+            .and_then(|path| attachment_page(&path))
+            .map(|page| vec![Event::PageChanged { page }])
+    };
+    // Folders are told apart by their missing extension once they are gone.
+    let folder = |path: &Path| {
+        watcher::relative_path(wiki_root, path)
+            .filter(|relative| !watcher::is_ignored(relative))
+            .filter(|relative| path.is_dir() || relative.extension().is_none())
+            .map(|relative| relative.to_string_lossy().replace('\\', "/"))
+    };
+    let tree = |path: &Path| {
+        attachment(path)
+            .or_else(|| folder(path).map(|path| vec![Event::TreeChanged { path }]))
+            .unwrap_or_default()
+    };
+
+    match change {
+        Change::Changed(path) => match page(path) {
+            Some(page) => vec![Event::PageChanged { page }],
+            None => attachment(path).unwrap_or_default(),
+        },
+        Change::Created(path) => match page(path) {
+            Some(page) if known(&page) => vec![Event::PageChanged { page }],
+            Some(page) => vec![
+                Event::PageCreated { page: page.clone() },
+                Event::TreeChanged { path: page },
+            ],
+            None => tree(path),
+        },
+        Change::Removed(path) => match page(path) {
+            Some(page) if !known(&page) => vec![],
+            Some(page) => vec![
+                Event::PageDeleted { page: page.clone() },
+                Event::TreeChanged { path: page },
+            ],
+            None => tree(path),
+        },
+        Change::Renamed(from, to) => match (page(from), page(to)) {
+            (Some(from), Some(to)) => vec![
+                Event::PageRenamed {
+                    from,
+                    to: to.clone(),
+                },
+                Event::TreeChanged { path: to },
+            ],
+            (Some(from), None) => vec![
+                Event::PageDeleted { page: from.clone() },
+                Event::TreeChanged { path: from },
+            ],
+            (None, Some(to)) => vec![
+                Event::PageCreated { page: to.clone() },
+                Event::TreeChanged { path: to },
+            ],
+            (None, None) => {
+                let mut events = tree(from);
+                events.extend(tree(to));
+                events
+            }
+        },
+        Change::Everything => vec![Event::Rescan],
+    }
+}
+
+/// The events for a batch of changes, each sent once.
+pub fn batch_events(
+    wiki_root: &Path,
+    changes: &[Change],
+    known: &dyn Fn(&str) -> bool,
+) -> Vec<Event> {
+    let mut events: Vec<Event> = vec![];
+    for event in changes
+        .iter()
+        .flat_map(|change| self::events(wiki_root, change, known))
+    {
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+    events
+}
+
+/// Events kept for the browsers that reconnect to get them.
+const HISTORY: usize = 256;
+
+/// How long browsers wait before asking for the next events. Every request
+/// to `/_/events` is answered right away, so that browsers don't hold on to
+/// the server's workers, and the browsers reconnect after this long.
+const RETRY: Duration = Duration::from_secs(2);
+
+/// The events sent since the server started, numbered from `first_id`.
+struct History {
+    first_id: u64,
+    events: VecDeque<Event>,
+}
+
+/// Tells the browsers what changed in the wiki.
+pub struct Refresher {
+    /// Tells apart the event ids of earlier runs of the server.
+    run: u64,
+    history: Mutex<History>,
+}
+
+impl Default for Refresher {
+    fn default() -> Self {
+        let run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Refresher::with_run(run)
+    }
+}
+
+impl Refresher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_run(run: u64) -> Self {
+        Refresher {
+            run,
+            history: Mutex::new(History {
+                first_id: 1,
+                events: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Called when a file in the wiki has changed.
+    pub fn send(&self, events: Vec<Event>, verbose: bool) {
+        let mut history = self.history.lock().unwrap();
+        for event in events {
+            if verbose {
+                println!("Queuing {:?} for the browsers", event);
+            }
+            history.events.push_back(event);
+            if history.events.len() > HISTORY {
+                history.events.pop_front();
+                history.first_id += 1;
+            }
+        }
+    }
+
+    /// The events since `last_event_id` for a browser showing `page`, among
+    /// the pages `user` can read. Browsers that connect for the first time
+    /// are only told where the events are at.
+    pub fn subscribe(
+        &self,
+        wiki_root: &Path,
+        user: &User,
+        page: Option<String>,
+        last_event_id: Option<&str>,
+    ) -> EventStream {
+        let subscriber = Subscriber {
+            access: Access::new(wiki_root, user),
+            page: page.map(|page| page.replace('\\', "/")),
+        };
+        let history = self.history.lock().unwrap();
+        let next_id = history.first_id + history.events.len() as u64;
+
+        let mut body = format!("retry: {}\n", RETRY.as_millis());
+        let last_id = last_event_id.map(|id| {
+            id.split_once(':')
+                .filter(|(run, _)| *run == self.run.to_string())
+                .and_then(|(_, id)| id.parse::<u64>().ok())
+        });
+        let missed: Vec<Event> = match last_id {
+            None => vec![],
+            // The server restarted, or the browser was away for too long.
+            Some(None) => vec![Event::Rescan],
+            Some(Some(id)) if id + 1 < history.first_id => vec![Event::Rescan],
+            Some(Some(id)) => history
+                .events
+                .iter()
+                .skip((id + 1).saturating_sub(history.first_id) as usize)
+                .cloned()
+                .collect(),
+        };
+        for event in missed.iter().filter(|event| subscriber.sends(event)) {
+            body.push_str(&format!(
+                "data: {}\n\n",
+                serde_json::to_string(event).unwrap()
+            ));
+        }
+        // An id without data is remembered by the browser, which sends it
+        // back when it reconnects.
+        body.push_str(&format!("id: {}:{}\n\n", self.run, next_id - 1));
+        EventStream(body)
+    }
+}
+
+/// A browser asking for events.
+struct Subscriber<'a> {
+    access: Access<'a>,
+    page: Option<String>,
+}
+
+impl<'a> Subscriber<'a> {
+    /// Events about pages the user can't read aren't sent.
+    fn sends(&self, event: &Event) -> bool {
+        if !event.concerns(self.page.as_deref()) {
+            return false;
+        }
+
+        let can_read = |page: &str| self.access.can_read(Path::new(page));
+        match event {
+            Event::PageChanged { page }
+            | Event::PageCreated { page }
+            | Event::PageDeleted { page }
+            | Event::TreeChanged { path: page } => can_read(page),
+            Event::PageRenamed { from, to } => can_read(from) && can_read(to),
+            Event::Rescan => true,
+        }
+    }
+}
+
+/// The `Last-Event-ID` header browsers send when they reconnect.
+pub struct LastEventId(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(LastEventId(
+            request
+                .headers()
+                .get_one("Last-Event-ID")
+                .map(str::to_string),
+        ))
+    }
+}
+
+/// A `text/event-stream` body with the events for one browser, each sent as
+/// a JSON `data:` line.
+pub struct EventStream(String);
+
+impl<'r> Responder<'r> for EventStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .sized_body(Cursor::new(self.0))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    #[test]
+    fn page_and_tree_events() {
+        let root = Path::new("/wiki");
+        let page = |page: &str| page.to_string();
+        let known = |page: &str| page == "page" || page == "old";
+        let events = |change: &Change| events(root, change, &known);
+
+        assert_eq!(
+            events(&Change::Changed(root.join("folder/page.md"))),
+            vec![Event::PageChanged {
+                page: page("folder/page")
+            }]
+        );
+        assert_eq!(
+            events(&Change::Removed(root.join("page.md"))),
+            vec![
+                Event::PageDeleted { page: page("page") },
+                Event::TreeChanged { path: page("page") },
+            ]
+        );
+        assert_eq!(
+            events(&Change::Renamed(
+                root.join("old.md"),
+                root.join("folder/new.md")
+            )),
+            vec![
+                Event::PageRenamed {
+                    from: page("old"),
+                    to: page("folder/new"),
+                },
+                Event::TreeChanged {
+                    path: page("folder/new")
+                },
+            ]
+        );
+        assert_eq!(
+            events(&Change::Removed(root.join("folder"))),
+            vec![Event::TreeChanged {
+                path: page("folder")
+            }]
+        );
+        assert!(events(&Change::Changed(root.join("image.png"))).is_empty());
+        assert!(events(&Change::Created(root.join(".simplewiki/index"))).is_empty());
+        assert!(events(&Change::Changed(root.join(".hidden/page.md"))).is_empty());
+    }
+
+    #[test]
+    fn changes_resolve_to_pages() {
+        let root = Path::new("/wiki");
+        let known = |page: &str| page == "a/b";
+        let changed = |page: &str| Event::PageChanged {
+            page: page.to_string(),
+        };
+
+        // Saved through a temporary file.
+        assert_eq!(
+            events(root, &Change::Created(root.join("a/b.md")), &known),
+            vec![changed("a/b")]
+        );
+        assert!(events(root, &Change::Removed(root.join("gone.md")), &known).is_empty());
+        assert_eq!(
+            batch_events(
+                root,
+                &[
+                    Change::Created(root.join("a/b.attachments")),
+                    Change::Created(root.join("a/b.attachments/image.png")),
+                ],
+                &known
+            ),
+            vec![changed("a/b")]
+        );
+    }
+
+    #[test]
+    fn browsers_get_events_about_their_page() {
+        let changed = Event::PageChanged {
+            page: "a".to_string(),
+        };
+        assert!(changed.concerns(Some("a")));
+        assert!(!changed.concerns(Some("b")));
+        assert!(!changed.concerns(None));
+        assert!(Event::Rescan.concerns(None));
+    }
+
+    #[test]
+    fn reconnecting_browsers_get_the_events_they_missed() {
+        let refresher = Refresher::with_run(7);
+        let user = User {
+            name: None,
+            role: Role::Admin,
+            groups: vec![],
+        };
+        let subscribe = |last_event_id: Option<&str>| {
+            refresher
+                .subscribe(
+                    Path::new("/wiki"),
+                    &user,
+                    Some("a".to_string()),
+                    last_event_id,
+                )
+                .0
+        };
+        let changed = |page: &str| Event::PageChanged {
+            page: page.to_string(),
+        };
+
+        assert_eq!(subscribe(None), "retry: 2000\nid: 7:0\n\n");
+        refresher.send(vec![changed("b"), changed("a")], false);
+        assert_eq!(
+            subscribe(Some("7:0")),
+            "retry: 2000\ndata: {\"type\":\"page_changed\",\"page\":\"a\"}\n\nid: 7:2\n\n"
+        );
+        assert_eq!(subscribe(Some("7:2")), "retry: 2000\nid: 7:2\n\n");
+        assert_eq!(
+            subscribe(Some("6:2")),
+            "retry: 2000\ndata: {\"type\":\"rescan\"}\n\nid: 7:2\n\n"
+        );
+
+        refresher.send(vec![changed("c"); HISTORY + 1], false);
+        assert!(subscribe(Some("7:2")).contains("rescan"));
+        assert_eq!(
+            subscribe(Some("7:3")),
+            format!("retry: 2000\nid: 7:{}\n\n", HISTORY + 3)
+        );
+    }
+}
@@ -11,27 +11,55 @@ extern crate error_chain;
 
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use std::fs;
-use std::fs::File;
 use std::io::prelude::*;
 
 use rocket::config::{Config, Environment};
 use rocket::http::uri::Uri;
-use rocket::request::Form;
+use rocket::http::ContentType;
+use rocket::http::Cookies;
+use rocket::http::Status;
+use rocket::request::{Form, FormItems, FromForm};
+use rocket::response::content::Html;
 use rocket::response::NamedFile;
 use rocket::response::Redirect;
-use rocket::State;
+use rocket::response::Responder;
+use rocket::{Data, State};
+use rocket_contrib::json::Json;
 use rocket_contrib::templates::Template;
 
-mod broadcaster;
+mod acl;
+mod api;
+mod atomic_file;
+mod attachments;
+mod audit;
+mod auth;
+mod authors;
+mod csrf;
+mod editor;
+mod file_format;
 mod free_port;
+mod index;
+mod links;
+mod live_reload;
 mod markdown;
-mod refresh_socket;
+mod page_template;
+mod query;
+mod replace;
+mod saved_search;
 mod search;
+mod section;
 mod static_file;
+mod trash;
 mod view;
+mod watcher;
+mod wiki_config;
 
+use crate::audit::ClientAddress;
+use crate::auth::{Admin, Editor, Viewer};
+use crate::csrf::{CsrfCheck, CsrfToken};
 use crate::markdown::MarkdownContext;
 use crate::static_file::StaticFile;
 
@@ -42,26 +70,57 @@ mod errors {
 use crate::errors::*;
 
 struct SiteConfig {
-    editor: String,
+    editor: editor::EditorCommand,
     wiki_root: PathBuf,
-    socket_port: u16,
+    /// Keep a `.bak` file with the previous content when saving a page.
+    backup: bool,
+    /// Serve the wiki without any routes that change it.
+    read_only: bool,
 }
 
+/// Used by `layout.html.tera`, and therefore by every page.
 #[derive(Serialize)]
-struct ShowContext {
+struct LayoutContext {
     view_groups: Vec<view::ViewGroup>,
+    /// Name of the logged in user, when authentication is enabled.
+    user: Option<String>,
+    /// Whether to show the buttons for creating and editing pages.
+    can_edit: bool,
+    is_admin: bool,
+    /// Submitted with every form and script request that changes something.
+    csrf_token: String,
+}
+
+fn layout_context(config: &SiteConfig, access: &acl::Access, csrf: &CsrfToken) -> LayoutContext {
+    let user = access.user();
+    LayoutContext {
+        view_groups: get_view_groups(&config.wiki_root, access),
+        user: user.name.clone(),
+        can_edit: !config.read_only && user.role >= auth::Role::Editor,
+        is_admin: user.role >= auth::Role::Admin,
+        csrf_token: csrf.as_str().to_string(),
+    }
+}
+
+#[derive(Serialize)]
+struct ShowContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
     content: String,
     title: String,
     prev_url: String,
     next_url: String,
     page: String,
-    socket_port: u16,
+    last_edit: Option<authors::LastEdit>,
+    /// Whether the access file of the folder lets the user edit this page.
+    can_edit_page: bool,
 }
 
 enum WikiResponse {
     NamedFile(NamedFile),
     Template(Template),
     Redirect(Redirect),
+    NotFound,
 }
 
 impl<'a> rocket::response::Responder<'a> for WikiResponse {
@@ -73,19 +132,166 @@ impl<'a> rocket::response::Responder<'a> for WikiResponse {
             WikiResponse::Template(x) => x.respond_to(request),
             WikiResponse::NamedFile(x) => x.respond_to(request),
             WikiResponse::Redirect(x) => x.respond_to(request),
+            WikiResponse::NotFound => Err(Status::NotFound),
         }
     }
 }
 
+#[derive(Serialize, Debug)]
+struct ErrorContext {
+    title: String,
+    message: String,
+}
+
+/// An error shown to the user as a page, with a status code.
+#[derive(Debug)]
+struct ErrorPage {
+    status: Status,
+    context: ErrorContext,
+}
+
+impl ErrorPage {
+    fn new(status: Status, title: &str, message: String) -> Self {
+        ErrorPage {
+            status,
+            context: ErrorContext {
+                title: title.to_string(),
+                message,
+            },
+        }
+    }
+
+    fn save_failed(page: &Path, error: io::Error) -> Self {
+        let message = format!("Could not save {}: {}", page.display(), error);
+        ErrorPage::new(Status::InternalServerError, "Failed to save page", message)
+    }
+
+    fn not_found(page: &Path) -> Self {
+        let message = format!("There is no page at {}", page.display());
+        ErrorPage::new(Status::NotFound, "Page not found", message)
+    }
+
+    fn forbidden(page: &Path) -> Self {
+        let message = format!("You are not allowed to change {}", page.display());
+        ErrorPage::new(Status::Forbidden, "Not allowed", message)
+    }
+}
+
+impl From<io::Error> for ErrorPage {
+    fn from(error: io::Error) -> Self {
+        ErrorPage::new(Status::InternalServerError, "Error", error.to_string())
+    }
+}
+
+impl From<Error> for ErrorPage {
+    fn from(error: Error) -> Self {
+        ErrorPage::new(Status::InternalServerError, "Error", error.to_string())
+    }
+}
+
+type PageResult<T> = std::result::Result<T, ErrorPage>;
+
+fn check_csrf(csrf: &CsrfCheck, submitted: Option<&str>) -> PageResult<()> {
+    if csrf.verify(submitted) {
+        Ok(())
+    } else {
+        Err(ErrorPage::new(
+            Status::Forbidden,
+            "Request expired",
+            "The request could not be verified. Reload the page and try again.".to_string(),
+        ))
+    }
+}
+
+/// Pages the user can't read are reported as missing, so that restricted
+/// pages can't be discovered by guessing their names.
+fn check_read(access: &acl::Access, path: &Path) -> PageResult<()> {
+    if access.can_read(path) {
+        Ok(())
+    } else {
+        Err(ErrorPage::not_found(path))
+    }
+}
+
+fn check_write(access: &acl::Access, path: &Path) -> PageResult<()> {
+    check_read(access, path)?;
+    if access.can_write(path) {
+        Ok(())
+    } else {
+        Err(ErrorPage::forbidden(path))
+    }
+}
+
+impl<'a> rocket::response::Responder<'a> for ErrorPage {
+    fn respond_to(self, request: &rocket::Request) -> rocket::response::Result<'a> {
+        let mut response = Template::render("error", self.context).respond_to(request)?;
+        response.set_status(self.status);
+        Ok(response)
+    }
+}
+
 #[get("/markdown/<path..>")]
-fn get_markdown(path: PathBuf, config: State<SiteConfig>) -> io::Result<String> {
+fn get_markdown(
+    path: PathBuf,
+    user: Viewer,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> PageResult<String> {
     let path = path_no_markdown(path);
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    check_read(&access, &path)?;
+
+    let mut markdown = MarkdownContext::new(&config.wiki_root, &path)?;
+    markdown.editable =
+        !config.read_only && user.0.role >= auth::Role::Editor && access.can_write(&path);
+    let searches = |options: &str, body: &str| {
+        render_search_block(options, body, &markdown.page, &access, &index, &config)
+    };
+    markdown
+        .html(&searches)
+        .ok_or_else(|| ErrorPage::not_found(&path))
+}
 
-    let markdown = MarkdownContext::new(&config.wiki_root, &path)?;
-    markdown.html().ok_or(io::Error::new(
-        io::ErrorKind::Other,
-        "No markdown exists for this page...",
-    ))
+/// Live reload events for the browser showing `page`, sent as server-sent
+/// events on the same port as the wiki. Routes that aren't pages are under
+/// `/_/`, so that they don't hide pages with the same name.
+#[get("/_/events?<page>")]
+fn live_events(
+    page: Option<String>,
+    user: Viewer,
+    last_event_id: live_reload::LastEventId,
+    refresher: State<Option<Arc<live_reload::Refresher>>>,
+    config: State<SiteConfig>,
+) -> PageResult<live_reload::EventStream> {
+    let refresher = refresher.as_ref().ok_or_else(|| {
+        ErrorPage::new(
+            Status::NotFound,
+            "Auto refresh is off",
+            "The wiki was started with --no-auto-refresh.".to_string(),
+        )
+    })?;
+    if let Some(ref page) = page {
+        check_read(
+            &acl::Access::new(&config.wiki_root, &user.0),
+            Path::new(page),
+        )?;
+    }
+    Ok(refresher.subscribe(&config.wiki_root, &user.0, page, last_event_id.0.as_deref()))
+}
+
+#[derive(Serialize)]
+struct NavContext {
+    view_groups: Vec<view::ViewGroup>,
+}
+
+/// The pages in the navbar, fetched again when pages are added or removed.
+#[get("/_/nav")]
+fn nav(user: Viewer, config: State<SiteConfig>) -> Template {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let context = NavContext {
+        view_groups: get_view_groups(&config.wiki_root, &access),
+    };
+    Template::render("nav_pages", context)
 }
 
 fn path_no_markdown(path: PathBuf) -> PathBuf {
@@ -96,31 +302,54 @@ fn path_no_markdown(path: PathBuf) -> PathBuf {
     p
 }
 
-#[get("/<path..>", rank = 2)]
-fn show(path: PathBuf, config: State<SiteConfig>) -> io::Result<WikiResponse> {
+#[get("/<path..>?<highlight>", rank = 2)]
+fn show(
+    path: PathBuf,
+    highlight: Option<String>,
+    user: Viewer,
+    csrf: CsrfToken,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> PageResult<WikiResponse> {
     let path = path_no_markdown(path);
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    check_read(&access, &path)?;
 
     if let Some(resp) = static_files(&config.wiki_root, &path) {
         return Ok(WikiResponse::NamedFile(resp));
     }
 
-    let markdown = MarkdownContext::new(&config.wiki_root, &path)?;
-    let view_groups = get_view_groups(&config.wiki_root);
+    let layout = layout_context(&config, &access, &csrf);
+    let can_edit_page = layout.can_edit && access.can_write(&path);
+    let mut markdown = MarkdownContext::new(&config.wiki_root, &path)?;
+    markdown.editable = can_edit_page;
 
     if markdown.exists() {
-        let prev_next = view::find_prev_next(&view_groups, &markdown.page);
+        let prev_next = view::find_prev_next(&layout.view_groups, &markdown.page);
+        // The terms of the search that led here are highlighted. A query that
+        // doesn't parse just isn't highlighted.
+        let highlight = highlight.and_then(|query| query::parse(&query).ok());
+        let highlights = highlight
+            .as_ref()
+            .map_or(vec![], |query| query.highlights());
+        let searches = |options: &str, body: &str| {
+            render_search_block(options, body, &markdown.page, &access, &index, &config)
+        };
 
         let context = ShowContext {
             prev_url: prev_next.prev.map_or("".into(), |p| p.file_name),
             next_url: prev_next.next.map_or("".into(), |p| p.file_name),
-            content: markdown.html().unwrap(),
+            content: markdown.highlighted_html(&highlights, &searches).unwrap(),
+            last_edit: authors::last_edit(&config.wiki_root, &markdown.page),
             title: markdown.title,
             page: markdown.page,
-            view_groups: view_groups,
-            socket_port: config.socket_port,
+            layout,
+            can_edit_page,
         };
 
         Ok(WikiResponse::Template(Template::render("show", &context)))
+    } else if !can_edit_page {
+        Ok(WikiResponse::NotFound)
     } else {
         let mut edit_path = PathBuf::from("edit");
         edit_path.push(&path);
@@ -133,36 +362,115 @@ fn static_files(wiki_root: &Path, file: &Path) -> Option<NamedFile> {
     NamedFile::open(file_path).ok()
 }
 
-fn get_view_groups(wiki_root: &Path) -> Vec<view::ViewGroup> {
+/// The pages shown in the navigation bar, and used for previous/next links.
+fn get_view_groups(wiki_root: &Path, access: &acl::Access) -> Vec<view::ViewGroup> {
     let view_finder = view::ViewFinder::new(wiki_root.to_owned());
-    view_finder
+    let mut view_groups = view_finder
         .get_groups()
-        .expect("Unable to read wiki directory")
+        .expect("Unable to read wiki directory");
+    view::retain_views(&mut view_groups, |view| {
+        access.can_read(Path::new(&view.file_name))
+    });
+    view_groups
 }
 
 #[derive(Serialize)]
 struct EditContext {
-    view_groups: Vec<view::ViewGroup>,
+    #[serde(flatten)]
+    layout: LayoutContext,
     content: String,
     title: String,
     page: String,
+    /// Set when only a single section of the page is edited.
+    section: Option<section::Section>,
+    file_hash: Option<String>,
+    /// Templates to pick from, when the page is new.
+    templates: Vec<String>,
+    template: Option<String>,
 }
 
-#[get("/edit/<path..>", rank = 1)]
-fn edit(path: PathBuf, config: State<SiteConfig>) -> io::Result<Template> {
+#[get("/edit/<path..>?<template>", rank = 1)]
+fn edit(
+    path: PathBuf,
+    template: Option<String>,
+    user: Editor,
+    csrf: CsrfToken,
+    config: State<SiteConfig>,
+) -> PageResult<Template> {
     let wiki_root = &config.wiki_root;
-    let markdown = MarkdownContext::new(wiki_root, &path)?;
+    let access = acl::Access::new(wiki_root, &user.0);
+    check_write(&access, &path)?;
+    let markdown = MarkdownContext::new(wiki_root, &path).chain_err(|| "Failed to read page")?;
+
+    let mut templates = vec![];
+    let mut template_name = None;
+    let content = match markdown.file_content {
+        Some(content) => content,
+        None => {
+            templates = page_template::list(wiki_root);
+            // An empty template name means that the user picked an empty page.
+            template_name = match template {
+                Some(name) => Some(name).filter(|name| !name.is_empty()),
+                None => page_template::default_for(wiki_root, &markdown.page),
+            };
+
+            match template_name {
+                Some(ref name) => {
+                    let template = page_template::load(wiki_root, name)?;
+                    page_template::fill(&template, &markdown.page, chrono::Local::now())
+                }
+                None => "".to_string(),
+            }
+        }
+    };
 
     let context = EditContext {
         title: markdown.title,
         page: markdown.page,
-        view_groups: get_view_groups(wiki_root),
-        content: markdown.file_content.unwrap_or("".to_string()),
+        layout: layout_context(&config, &access, &csrf),
+        content,
+        section: None,
+        file_hash: None,
+        templates,
+        template: template_name,
     };
 
     Ok(Template::render("edit", &context))
 }
 
+#[get("/edit_section/<path..>?<section>", rank = 1)]
+fn edit_section(
+    path: PathBuf,
+    section: usize,
+    user: Editor,
+    csrf: CsrfToken,
+    config: State<SiteConfig>,
+) -> PageResult<Template> {
+    let wiki_root = &config.wiki_root;
+    let access = acl::Access::new(wiki_root, &user.0);
+    check_write(&access, &path)?;
+    let markdown = MarkdownContext::new(wiki_root, &path).chain_err(|| "Failed to read page")?;
+    let file_content = markdown.file_content.chain_err(|| "Page does not exist")?;
+
+    let section = section::sections(&file_content)
+        .into_iter()
+        .nth(section)
+        .chain_err(|| "Section does not exist")?;
+
+    let context = EditContext {
+        title: section.title.clone(),
+        page: markdown.page,
+        layout: layout_context(&config, &access, &csrf),
+        content: file_content[section.start..section.end].to_string(),
+        file_hash: Some(section::content_hash(&file_content)),
+        section: Some(section),
+        templates: vec![],
+        template: None,
+    };
+
+    Ok(Template::render("edit", context))
+}
+
 #[get("/static/<path..>", rank = 1)]
 fn static_file(path: PathBuf) -> io::Result<StaticFile> {
     Ok(StaticFile::new(path))
@@ -171,28 +479,192 @@ fn static_file(path: PathBuf) -> io::Result<StaticFile> {
 #[derive(FromForm)]
 struct EditForm {
     content: String,
+    csrf_token: Option<String>,
+}
+
+/// Posted by buttons and links that change something.
+#[derive(FromForm)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// Appends to the audit log. The change has already been made, so a failure
+/// here is only reported.
+fn audit(config: &SiteConfig, entry: &audit::AuditEntry) {
+    if let Err(e) = audit::append(&config.wiki_root, entry) {
+        println!("Failed to write audit log entry for {}: {}", entry.page, e);
+    }
+}
+
+/// Records who saved a page, and how it changed. `old_hash` is the hash of
+/// the file before it was saved.
+fn record_save(
+    config: &SiteConfig,
+    action: audit::Action,
+    markdown: &MarkdownContext,
+    old_hash: Option<String>,
+    user: &auth::User,
+    client: &ClientAddress,
+) {
+    if let Some(ref name) = user.name {
+        if let Err(e) = authors::record(&config.wiki_root, &markdown.page, name) {
+            println!(
+                "Failed to record {} as author of {}: {}",
+                name, markdown.page, e
+            );
+        }
+    }
+
+    let mut entry = audit::AuditEntry::new(action, &markdown.page, user.name.as_deref(), client);
+    entry.old_hash = old_hash;
+    entry.new_hash = audit::file_hash(&markdown.file_path);
+    audit(config, &entry);
 }
 
 #[post("/edit/<path..>", data = "<content>")]
 fn edit_post(
     path: PathBuf,
     content: Form<EditForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    client: ClientAddress,
     config: State<SiteConfig>,
-) -> io::Result<Redirect> {
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, content.csrf_token.as_deref())?;
+    check_write(&acl::Access::new(&config.wiki_root, &user.0), &path)?;
     let new_content = content.into_inner().content;
 
-    let context = MarkdownContext::new(&config.wiki_root, &path)?;
+    let save_failed = |e| ErrorPage::save_failed(&path, e);
+    let context = MarkdownContext::new(&config.wiki_root, &path).map_err(save_failed)?;
+    let old_hash = audit::file_hash(&context.file_path);
+    context
+        .save(&new_content, config.backup)
+        .map_err(save_failed)?;
+    record_save(
+        &config,
+        audit::Action::Save,
+        &context,
+        old_hash,
+        &user.0,
+        &client,
+    );
 
-    if let Some(context_folder) = Path::new(&context.file_path).parent() {
-        fs::create_dir_all(context_folder)?;
+    Ok(redirect_to_path(&path))
+}
+
+#[derive(FromForm)]
+struct SectionEditForm {
+    content: String,
+    start: usize,
+    end: usize,
+    file_hash: String,
+    csrf_token: Option<String>,
+}
+
+#[post("/edit_section/<path..>", data = "<form>")]
+fn edit_section_post(
+    path: PathBuf,
+    form: Form<SectionEditForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    client: ClientAddress,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    check_write(&acl::Access::new(&config.wiki_root, &user.0), &path)?;
+    let save_failed = |e| ErrorPage::save_failed(&path, e);
+    let conflict = || {
+        ErrorPage::new(
+            Status::Conflict,
+            "The page was changed",
+            "The page was changed while you were editing this section. \
+             Open the section again and reapply your changes."
+                .to_string(),
+        )
+    };
+
+    let context = MarkdownContext::new(&config.wiki_root, &path).map_err(save_failed)?;
+    let file_content = context.file_content.as_ref().ok_or_else(conflict)?;
+
+    if section::content_hash(file_content) != form.file_hash {
+        return Err(conflict());
     }
 
-    let mut file = File::create(&context.file_path)?;
-    file.write_all(new_content.as_bytes())?;
+    let new_content =
+        section::splice(file_content, form.start, form.end, &form.content).ok_or_else(conflict)?;
+    let old_hash = audit::file_hash(&context.file_path);
+    context
+        .save(&new_content, config.backup)
+        .map_err(save_failed)?;
+    record_save(
+        &config,
+        audit::Action::Save,
+        &context,
+        old_hash,
+        &user.0,
+        &client,
+    );
 
     Ok(redirect_to_path(&path))
 }
 
+#[post("/preview/<path..>", data = "<content>")]
+fn preview(
+    path: PathBuf,
+    content: Form<EditForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> PageResult<Html<String>> {
+    check_csrf(&csrf, content.csrf_token.as_deref())?;
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    check_write(&access, &path)?;
+    let markdown = MarkdownContext::new(&config.wiki_root, &path)?;
+    let searches = |options: &str, body: &str| {
+        render_search_block(options, body, &markdown.page, &access, &index, &config)
+    };
+    Ok(Html(markdown.preview_html(&content.content, &searches)))
+}
+
+#[post("/upload/<path..>", data = "<data>")]
+fn upload(
+    path: PathBuf,
+    content_type: &ContentType,
+    data: Data,
+    user: Editor,
+    csrf: CsrfCheck,
+    client: ClientAddress,
+    config: State<SiteConfig>,
+) -> PageResult<Json<Vec<attachments::Attachment>>> {
+    check_csrf(&csrf, None)?;
+    check_write(&acl::Access::new(&config.wiki_root, &user.0), &path)?;
+    let markdown =
+        MarkdownContext::new(&config.wiki_root, &path).chain_err(|| "Failed to read page")?;
+
+    let boundary = content_type
+        .params()
+        .find(|&(name, _)| name == "boundary")
+        .map(|(_, value)| value)
+        .chain_err(|| "Expected a multipart/form-data upload")?;
+
+    let uploaded = attachments::save_multipart(&markdown.file_path, boundary, data.open())?;
+
+    let dir = attachments::attachments_dir(&markdown.file_path);
+    for attachment in &uploaded {
+        let mut entry = audit::AuditEntry::new(
+            audit::Action::Upload,
+            &markdown.page,
+            user.0.name.as_deref(),
+            &client,
+        );
+        entry.new_hash = audit::file_hash(&dir.join(&attachment.file_name));
+        entry.file_name = Some(attachment.file_name.clone());
+        audit(&config, &entry);
+    }
+    Ok(Json(uploaded))
+}
+
 fn redirect_to_path(path: &Path) -> Redirect {
     use rocket::http::ext::IntoOwned;
     let path_str = path
@@ -206,30 +678,473 @@ fn redirect_to_path(path: &Path) -> Redirect {
     Redirect::to(uri)
 }
 
-#[get("/edit_editor/<path..>", rank = 1)]
-fn edit_editor(path: PathBuf, config: State<SiteConfig>) -> io::Result<Redirect> {
-    use std::process::Command;
+/// A post, since it starts a process on the server.
+#[post("/edit_editor/<path..>?<line>&<column>", data = "<form>")]
+fn edit_editor(
+    path: PathBuf,
+    line: Option<usize>,
+    column: Option<usize>,
+    form: Form<CsrfForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    check_write(&acl::Access::new(&config.wiki_root, &user.0), &path)?;
+    let markdown = MarkdownContext::new(&config.wiki_root, &path)?;
+
+    config
+        .editor
+        .spawn(&markdown.file_path, line.unwrap_or(1), column.unwrap_or(1))?;
+
+    Ok(redirect_to_path(&path))
+}
+
+#[derive(Serialize)]
+struct DeleteContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
+    title: String,
+    page: String,
+    backlinks: Vec<String>,
+}
 
+#[get("/delete/<path..>", rank = 1)]
+fn delete(
+    path: PathBuf,
+    user: Editor,
+    csrf: CsrfToken,
+    config: State<SiteConfig>,
+) -> PageResult<Template> {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    check_write(&access, &path)?;
     let markdown = MarkdownContext::new(&config.wiki_root, &path)?;
 
-    let editor = &config.editor;
+    let mut backlinks = links::find_backlinks(&config.wiki_root, &markdown.page);
+    backlinks.retain(|page| access.can_read(Path::new(page)));
 
-    Command::new(editor).arg(&markdown.file_path).status()?;
+    let context = DeleteContext {
+        title: format!("Delete {}", &markdown.title),
+        backlinks,
+        page: markdown.page,
+        layout: layout_context(&config, &access, &csrf),
+    };
 
-    Ok(redirect_to_path(&path))
+    Ok(Template::render("delete", context))
+}
+
+#[post("/delete/<path..>", data = "<form>")]
+fn delete_post(
+    path: PathBuf,
+    form: Form<CsrfForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    client: ClientAddress,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    check_write(&acl::Access::new(&config.wiki_root, &user.0), &path)?;
+    let markdown =
+        MarkdownContext::new(&config.wiki_root, &path).chain_err(|| "Failed to read page")?;
+
+    if !markdown.exists() {
+        return Err(ErrorPage::not_found(&path));
+    }
+
+    let mut entry = audit::AuditEntry::new(
+        audit::Action::Delete,
+        &markdown.page,
+        user.0.name.as_deref(),
+        &client,
+    );
+    entry.old_hash = audit::file_hash(&markdown.file_path);
+
+    trash::delete(
+        &config.wiki_root,
+        &markdown.page,
+        &markdown.file_path,
+        user.0.name.as_deref(),
+    )?;
+    audit(&config, &entry);
+
+    Ok(Redirect::to("/_/trash"))
+}
+
+#[derive(Serialize)]
+struct TrashContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
+    title: String,
+    entries: Vec<trash::TrashEntry>,
+    can_purge: bool,
+}
+
+#[get("/_/trash")]
+fn trash_page(user: Editor, csrf: CsrfToken, config: State<SiteConfig>) -> Result<Template> {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let mut entries = trash::list(&config.wiki_root)?;
+    entries.retain(|entry| access.can_read(Path::new(&entry.page)));
+
+    let context = TrashContext {
+        title: "Trash".to_string(),
+        entries,
+        can_purge: user.0.role >= auth::Role::Admin,
+        layout: layout_context(&config, &access, &csrf),
+    };
+
+    Ok(Template::render("trash", context))
+}
+
+#[post("/_/trash/restore/<id>", data = "<form>")]
+fn trash_restore(
+    id: String,
+    form: Form<CsrfForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    client: ClientAddress,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    let entry = trash::get(&config.wiki_root, &id)?;
+    check_write(
+        &acl::Access::new(&config.wiki_root, &user.0),
+        Path::new(&entry.page),
+    )?;
+
+    let entry = trash::restore(&config.wiki_root, &id)?;
+
+    let mut audit_entry = audit::AuditEntry::new(
+        audit::Action::Restore,
+        &entry.page,
+        user.0.name.as_deref(),
+        &client,
+    );
+    audit_entry.new_hash = audit::file_hash(&config.wiki_root.join(&entry.original_path));
+    audit(&config, &audit_entry);
+
+    Ok(redirect_to_path(Path::new(&entry.page)))
+}
+
+#[post("/_/trash/purge/<id>", data = "<form>")]
+fn trash_purge(
+    id: String,
+    form: Form<CsrfForm>,
+    user: Admin,
+    csrf: CsrfCheck,
+    client: ClientAddress,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    let entry = trash::purge(&config.wiki_root, &id)?;
+
+    let audit_entry = audit::AuditEntry::new(
+        audit::Action::Purge,
+        &entry.page,
+        user.0.name.as_deref(),
+        &client,
+    );
+    audit(&config, &audit_entry);
+
+    Ok(Redirect::to("/_/trash"))
+}
+
+#[derive(Serialize)]
+struct ReplaceContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
+    title: String,
+    find: String,
+    replace: String,
+    changes: Vec<replace::FileChange>,
+    /// Whether the search ran out of time, so that some pages may be missing.
+    truncated: bool,
+    /// Pages that were just changed.
+    replaced: Vec<String>,
+    error: Option<String>,
+    syntax_help: &'static str,
+}
+
+/// Previews replacing `find` with `replace` in every page the user can edit.
+/// `find` is a search query with a single term to replace.
+fn preview_replace(
+    find: &str,
+    replace: &str,
+    access: &acl::Access,
+    index: &index::SearchIndex,
+    config: &SiteConfig,
+) -> Result<(Vec<replace::FileChange>, bool)> {
+    let query = query::parse(find)?;
+    let (regex, literal) = query.replacement_term()?;
+    let dir = config.wiki_root.as_os_str().to_str().unwrap().to_string();
+    let found = search::find_pages(
+        &query,
+        &dir,
+        |path| access.can_write(path),
+        index,
+        search::SearchOptions::default().time_budget,
+    );
+    let pages: Vec<String> = found.pages.into_iter().map(|(page, _)| page).collect();
+    let replacer = replace::Replacer::new(regex, replace, literal);
+    Ok((
+        replace::preview(&config.wiki_root, &pages, &replacer),
+        found.truncated,
+    ))
+}
+
+#[get("/replace?<find>&<replace>")]
+fn replace_page(
+    find: Option<String>,
+    replace: Option<String>,
+    user: Editor,
+    csrf: CsrfToken,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> Template {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let find = find.unwrap_or_default();
+    let replace = replace.unwrap_or_default();
+    let (changes, truncated, error) = if find.is_empty() {
+        (vec![], false, None)
+    } else {
+        match preview_replace(&find, &replace, &access, &index, &config) {
+            Ok((changes, truncated)) => (changes, truncated, None),
+            Err(e) => (vec![], false, Some(e.to_string())),
+        }
+    };
+
+    let context = ReplaceContext {
+        title: "Find and replace".to_string(),
+        find,
+        replace,
+        changes,
+        truncated,
+        replaced: vec![],
+        error,
+        syntax_help: query::SYNTAX_HELP,
+        layout: layout_context(&config, &access, &csrf),
+    };
+    Template::render("replace", context)
+}
+
+/// The pages selected in the preview, each given as `hash:page` with the
+/// hash of the content the preview was made from.
+struct ReplaceForm {
+    find: String,
+    replace: String,
+    pages: Vec<(String, String)>,
+    csrf_token: Option<String>,
+}
+
+impl<'f> FromForm<'f> for ReplaceForm {
+    type Error = ();
+
+    fn from_form(
+        items: &mut FormItems<'f>,
+        _strict: bool,
+    ) -> std::result::Result<Self, Self::Error> {
+        let mut form = ReplaceForm {
+            find: String::new(),
+            replace: String::new(),
+            pages: vec![],
+            csrf_token: None,
+        };
+        for item in items {
+            let (key, value) = item.key_value_decoded();
+            match key.as_str() {
+                "find" => form.find = value,
+                "replace" => form.replace = value,
+                "csrf_token" => form.csrf_token = Some(value),
+                "page" => {
+                    let (hash, page) = value.split_once(':').ok_or(())?;
+                    form.pages.push((page.to_string(), hash.to_string()));
+                }
+                _ => return Err(()),
+            }
+        }
+        Ok(form)
+    }
+}
+
+#[post("/replace", data = "<form>")]
+fn replace_post(
+    form: Form<ReplaceForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    token: CsrfToken,
+    client: ClientAddress,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> PageResult<Template> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    for (page, _) in &form.pages {
+        replace::check_page(page)?;
+        check_write(&access, Path::new(page))?;
+    }
+
+    let query = query::parse(&form.find)?;
+    let (regex, literal) = query.replacement_term()?;
+    let replacer = replace::Replacer::new(regex, &form.replace, literal);
+    let (replaced, error) =
+        match replace::apply(&config.wiki_root, &form.pages, &replacer, config.backup) {
+            Ok(applied) => {
+                for page in &applied {
+                    record_save(
+                        &config,
+                        audit::Action::Replace,
+                        &page.markdown,
+                        page.old_hash.clone(),
+                        &user.0,
+                        &client,
+                    );
+                }
+                let pages = applied.into_iter().map(|page| page.markdown.page).collect();
+                (pages, None)
+            }
+            Err(e) => (vec![], Some(e.to_string())),
+        };
+
+    // Show what is left to replace, such as the pages that weren't selected.
+    let (changes, truncated) =
+        preview_replace(&form.find, &form.replace, &access, &index, &config)?;
+    let context = ReplaceContext {
+        title: "Find and replace".to_string(),
+        find: form.find.clone(),
+        replace: form.replace.clone(),
+        changes,
+        truncated,
+        replaced,
+        error,
+        syntax_help: query::SYNTAX_HELP,
+        layout: layout_context(&config, &access, &token),
+    };
+    Ok(Template::render("replace", context))
+}
+
+#[derive(Serialize)]
+struct SavedSearchesContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
+    title: String,
+    searches: Vec<saved_search::SavedSearch>,
+}
+
+#[get("/saved_searches")]
+fn saved_searches(user: Viewer, csrf: CsrfToken, config: State<SiteConfig>) -> Template {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let context = SavedSearchesContext {
+        title: "Saved searches".to_string(),
+        searches: saved_search::list(&config.wiki_root),
+        layout: layout_context(&config, &access, &csrf),
+    };
+    Template::render("saved_searches", context)
+}
+
+#[derive(FromForm)]
+struct SavedSearchForm {
+    name: String,
+    pattern: String,
+    csrf_token: Option<String>,
+}
+
+#[post("/saved_searches", data = "<form>")]
+fn saved_search_post(
+    form: Form<SavedSearchForm>,
+    user: Editor,
+    csrf: CsrfCheck,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    query::parse(&form.pattern)
+        .and_then(|_| {
+            saved_search::save(
+                &config.wiki_root,
+                &form.name,
+                &form.pattern,
+                user.0.name.as_deref(),
+            )
+        })
+        .map_err(|e| {
+            ErrorPage::new(
+                Status::BadRequest,
+                "Failed to save the search",
+                e.to_string(),
+            )
+        })?;
+    Ok(Redirect::to("/saved_searches"))
+}
+
+#[post("/saved_searches/delete/<name>", data = "<form>")]
+fn saved_search_delete(
+    name: String,
+    form: Form<CsrfForm>,
+    _user: Editor,
+    csrf: CsrfCheck,
+    config: State<SiteConfig>,
+) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    saved_search::delete(&config.wiki_root, &name)?;
+    Ok(Redirect::to("/saved_searches"))
+}
+
+/// The audit page shows at most this many entries, newest first.
+const AUDIT_PAGE_SIZE: usize = 500;
+
+#[derive(Serialize)]
+struct AuditContext {
+    #[serde(flatten)]
+    layout: LayoutContext,
+    title: String,
+    entries: Vec<audit::AuditEntry>,
+    limit: usize,
+    filter_user: String,
+    filter_action: String,
+    filter_page: String,
+}
+
+#[get("/_/audit?<user>&<action>&<page>")]
+fn audit_page(
+    user: Option<String>,
+    action: Option<String>,
+    page: Option<String>,
+    admin: Admin,
+    csrf: CsrfToken,
+    config: State<SiteConfig>,
+) -> Result<Template> {
+    let non_empty = |value: &Option<String>| value.clone().filter(|v| !v.is_empty());
+    let filter = audit::Filter {
+        user: non_empty(&user),
+        action: non_empty(&action).and_then(|action| audit::Action::parse(&action)),
+        page: non_empty(&page),
+    };
+
+    let access = acl::Access::new(&config.wiki_root, &admin.0);
+    let context = AuditContext {
+        layout: layout_context(&config, &access, &csrf),
+        title: "Audit log".to_string(),
+        entries: audit::read(&config.wiki_root, &filter, AUDIT_PAGE_SIZE)?,
+        limit: AUDIT_PAGE_SIZE,
+        filter_user: user.unwrap_or_default(),
+        filter_action: action.unwrap_or_default(),
+        filter_page: page.unwrap_or_default(),
+    };
+
+    Ok(Template::render("audit", context))
 }
 
 #[derive(Serialize)]
 struct IndexContent {
     title: String,
-    view_groups: Vec<view::ViewGroup>,
+    #[serde(flatten)]
+    layout: LayoutContext,
 }
 
 #[get("/")]
-fn index(config: State<SiteConfig>) -> Template {
+fn index(user: Viewer, csrf: CsrfToken, config: State<SiteConfig>) -> Template {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
     let content = IndexContent {
         title: "Home".to_string(),
-        view_groups: get_view_groups(&config.wiki_root),
+        layout: layout_context(&config, &access, &csrf),
     };
 
     Template::render("index", &content)
@@ -238,31 +1153,283 @@ fn index(config: State<SiteConfig>) -> Template {
 #[derive(FromForm)]
 struct SearchQuery {
     pattern: String,
+    /// Lines shown around every match.
+    context: Option<usize>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl SearchQuery {
+    fn options(&self) -> search::SearchOptions {
+        search::SearchOptions {
+            context: self.context.unwrap_or(search::DEFAULT_CONTEXT),
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit.unwrap_or(search::DEFAULT_LIMIT),
+            ..Default::default()
+        }
+    }
+}
+
+/// Runs a search for the search page and the API. The inner error is the
+/// reason the query couldn't be parsed, which is shown to the user.
+fn run_search(
+    params: &SearchQuery,
+    access: &acl::Access,
+    index: &index::SearchIndex,
+    config: &SiteConfig,
+) -> errors::Result<std::result::Result<search::SearchResult, String>> {
+    let query = match query::parse(&params.pattern) {
+        Ok(query) => query,
+        Err(e) => return Ok(Err(e.to_string())),
+    };
+    let dir = config.wiki_root.as_os_str().to_str().unwrap().to_string();
+    let result = search::search(
+        &query,
+        &params.options(),
+        &dir,
+        get_page_url,
+        |path| access.can_read(path),
+        index,
+    )
+    .chain_err(|| "Search failed")?;
+    Ok(Ok(result))
+}
+
+/// Renders a `search` block of `page` with the pages its query finds now,
+/// among the others the user can read.
+fn render_search_block(
+    options: &str,
+    body: &str,
+    page: &str,
+    access: &acl::Access,
+    index: &index::SearchIndex,
+    config: &SiteConfig,
+) -> String {
+    let found = saved_search::parse_block(&config.wiki_root, options, body).and_then(|block| {
+        let query = query::parse(&block.query)?;
+        let dir = config.wiki_root.as_os_str().to_str().unwrap().to_string();
+        let found = search::find_pages(
+            &query,
+            &dir,
+            |path| access.can_read(path) && path.with_extension("") != Path::new(page),
+            index,
+            search::SearchOptions::default().time_budget,
+        );
+        Ok((block, found))
+    });
+
+    match found {
+        Ok((block, found)) => {
+            let pages: Vec<(&str, &index::Document)> = found
+                .pages
+                .iter()
+                .zip(&found.documents)
+                .take(block.limit)
+                .map(|((page, _), document)| (page.as_str(), document))
+                .collect();
+            saved_search::block_html(&block, &pages, found.pages.len())
+        }
+        Err(e) => saved_search::block_error_html(&e),
+    }
 }
 
 #[derive(Serialize)]
 struct SearchResult {
-    result: search::SearchResult,
+    result: Option<search::SearchResult>,
+    /// Why the query couldn't be parsed.
+    error: Option<String>,
+    syntax_help: &'static str,
     title: String,
     pattern: String,
-    view_groups: Vec<view::ViewGroup>,
+    /// Kept when showing more results.
+    context: usize,
+    limit: usize,
+    #[serde(flatten)]
+    layout: LayoutContext,
 }
 
 #[get("/search?<query..>")]
-fn search(query: Form<SearchQuery>, config: State<SiteConfig>) -> errors::Result<Template> {
+fn search(
+    query: Form<SearchQuery>,
+    user: Viewer,
+    csrf: CsrfToken,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> errors::Result<Template> {
     let pattern = query.pattern.as_str();
-    let dir = config.wiki_root.as_os_str().to_str().unwrap().to_string();
-    let result: search::SearchResult =
-        search::search(pattern, &dir, get_page_url).chain_err(|| "Search failed")?;
+    let options = query.options();
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let (result, error) = match run_search(&query, &access, &index, &config)? {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
     let result = SearchResult {
-        title: format!("Search results for '{}'", &result.pattern),
-        pattern: result.pattern.clone(),
+        title: format!("Search results for '{}'", pattern),
+        pattern: pattern.to_string(),
         result,
-        view_groups: get_view_groups(&config.wiki_root),
+        error,
+        syntax_help: query::SYNTAX_HELP,
+        context: options.context,
+        limit: options.limit,
+        layout: layout_context(&config, &access, &csrf),
     };
     Ok(Template::render("search-result", &result))
 }
 
+type ApiResult<T> = std::result::Result<Json<T>, api::ApiError>;
+
+#[get("/api/v1/search?<query..>")]
+fn api_search(
+    query: Form<SearchQuery>,
+    user: Viewer,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> ApiResult<api::SearchResponse> {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    match run_search(&query, &access, &index, &config)? {
+        Ok(result) => Ok(Json(api::SearchResponse::new(&result))),
+        Err(error) => Err(api::ApiError::new(Status::BadRequest, &error)),
+    }
+}
+
+/// Pages whose names or titles match `term`, for autocompletion.
+#[get("/api/v1/titles?<term>&<limit>")]
+fn api_titles(
+    term: String,
+    limit: Option<usize>,
+    user: Viewer,
+    index: State<Arc<index::SearchIndex>>,
+    config: State<SiteConfig>,
+) -> ApiResult<Vec<api::Title>> {
+    let access = acl::Access::new(&config.wiki_root, &user.0);
+    let words: Vec<String> = index::tokenize(&term).collect();
+    let dir = config.wiki_root.as_os_str().to_str().unwrap().to_string();
+    let titles = search::search_titles(
+        &words,
+        limit.unwrap_or(api::DEFAULT_TITLES),
+        &dir,
+        &get_page_url,
+        &|path: &Path| access.can_read(path),
+        &index,
+    )?;
+    Ok(Json(api::titles(&titles)))
+}
+
+#[derive(Serialize)]
+struct LoginContext {
+    title: String,
+    next: String,
+    error: Option<String>,
+    csrf_token: String,
+}
+
+#[get("/login?<next>")]
+fn login_page(next: Option<String>, csrf: CsrfToken) -> Template {
+    let context = LoginContext {
+        title: "Log in".to_string(),
+        next: next.unwrap_or_else(|| "/".to_string()),
+        error: None,
+        csrf_token: csrf.as_str().to_string(),
+    };
+    Template::render("login", context)
+}
+
+#[derive(FromForm)]
+struct LoginForm {
+    name: String,
+    password: String,
+    next: String,
+    csrf_token: Option<String>,
+}
+
+#[post("/login", data = "<form>")]
+fn login(
+    form: Form<LoginForm>,
+    csrf: CsrfCheck,
+    mut cookies: Cookies,
+    auth: State<auth::Auth>,
+) -> PageResult<WikiResponse> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    let role = auth
+        .users
+        .as_ref()
+        .and_then(|users| users.verify(&form.name, &form.password));
+
+    if role.is_none() {
+        let context = LoginContext {
+            title: "Log in".to_string(),
+            next: form.next.clone(),
+            error: Some("Wrong user name or password".to_string()),
+            csrf_token: form.csrf_token.clone().unwrap_or_default(),
+        };
+        return Ok(WikiResponse::Template(Template::render("login", context)));
+    }
+
+    auth::log_in(&mut cookies, &form.name);
+
+    // Only redirect within the wiki.
+    if form.next.starts_with('/') && !form.next.starts_with("//") {
+        Ok(WikiResponse::Redirect(Redirect::to(form.next.clone())))
+    } else {
+        Ok(WikiResponse::Redirect(Redirect::to("/")))
+    }
+}
+
+#[post("/logout", data = "<form>")]
+fn logout(form: Form<CsrfForm>, csrf: CsrfCheck, mut cookies: Cookies) -> PageResult<Redirect> {
+    check_csrf(&csrf, form.csrf_token.as_deref())?;
+    auth::log_out(&mut cookies);
+    Ok(Redirect::to("/login"))
+}
+
+#[catch(401)]
+fn unauthorized<'r>(request: &rocket::Request) -> rocket::response::Result<'r> {
+    if api::is_api(request.uri().path()) {
+        return api::ApiError::new(Status::Unauthorized, "Not logged in").respond_to(request);
+    }
+    let next = request.uri().to_string();
+    Redirect::to(format!("/login?next={}", Uri::percent_encode(&next))).respond_to(request)
+}
+
+// In read-only mode, these replace the routes that change the wiki.
+
+#[get("/edit/<_path..>", rank = 1)]
+fn read_only_edit(_path: PathBuf) -> Status {
+    Status::Forbidden
+}
+
+#[get("/edit_section/<_path..>", rank = 1)]
+fn read_only_edit_section(_path: PathBuf) -> Status {
+    Status::Forbidden
+}
+
+#[get("/delete/<_path..>", rank = 1)]
+fn read_only_delete(_path: PathBuf) -> Status {
+    Status::Forbidden
+}
+
+#[get("/_/trash")]
+fn read_only_trash() -> Status {
+    Status::Forbidden
+}
+
+#[get("/replace")]
+fn read_only_replace() -> Status {
+    Status::Forbidden
+}
+
+#[post("/<_path..>")]
+fn read_only_post(_path: PathBuf) -> Status {
+    Status::Forbidden
+}
+
+/// Where `csrf::HostCheck` sends requests for hosts the wiki doesn't answer
+/// to. Must match `csrf::FORBIDDEN_HOST_URI`.
+#[get("/_/forbidden-host")]
+fn forbidden_host() -> Status {
+    Status::Forbidden
+}
+
 fn get_page_url(file_path: &Path, wiki_root: &Path) -> Result<String> {
     let relative_path: &Path = wiki_root
         .strip_prefix(file_path)
@@ -317,6 +1484,19 @@ fn run() -> Result<()> {
                 .takes_value(true)
                 .help("The address you want the server to serve on. Default: localhost"),
         )
+        .arg(
+            Arg::with_name("allowed_host")
+                .long("allowed-host")
+                .value_name("HOST")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Also answer requests for this host name, such as the one a reverse \
+                     proxy forwards. A host without a port is allowed on any port. \
+                     Use * for any host",
+                ),
+        )
         .arg(
             Arg::with_name("wiki_root")
                 .index(1)
@@ -328,27 +1508,121 @@ fn run() -> Result<()> {
                 .long("editor")
                 .short("e")
                 .takes_value(true)
-                .help("Defaults to subl"),
+                .help(
+                    "Command used to open pages in an external editor. \
+                     {file}, {line} and {column} are replaced. \
+                     Default: subl {file}:{line}:{column}",
+                ),
         )
         .arg(
-            Arg::with_name("skip_websocket")
+            Arg::with_name("trusted_proxy")
+                .long("trusted-proxy")
+                .value_name("IP")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Record the X-Real-IP header in the audit log for requests from \
+                     this reverse proxy, instead of the address of the proxy",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_auto_refresh")
                 .long("no-auto-refresh")
-                .help("Don't start websocket with refresh-ability"),
+                .help("Don't refresh the pages in the browser when their files change"),
+        )
+        .arg(
+            Arg::with_name("debounce")
+                .long("debounce")
+                .value_name("MS")
+                .takes_value(true)
+                .help(
+                    "Wait until no file changed for this many milliseconds before \
+                     refreshing the pages and the search index. Default: 250",
+                ),
         )
         .arg(
             Arg::with_name("skip_open")
                 .long("skip-open")
                 .help("Don't open the wiki page in your web browser at startup"),
         )
+        .arg(
+            Arg::with_name("backup")
+                .long("backup")
+                .help("Keep the previous version of a page in a .bak file when saving it"),
+        )
+        .arg(Arg::with_name("read_only").long("read-only").help(
+            "Serve the wiki without the ability to edit, create or delete pages. \
+                     Also set by \"read_only\": true in .simplewiki/config.json",
+        ))
+        .arg(
+            Arg::with_name("users")
+                .long("users")
+                .value_name("FILE")
+                .takes_value(true)
+                .help(
+                    "Require users to log in. Every line of the file is name:role:hash, \
+                     where role is viewer, editor or admin",
+                ),
+        )
+        .arg(
+            Arg::with_name("secret_key_file")
+                .long("secret-key-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help(
+                    "Where the key that signs login cookies is kept, created if missing. \
+                     Default: .simplewiki/secret_key in the wiki folder",
+                ),
+        )
+        .arg(
+            Arg::with_name("hash_password")
+                .long("hash-password")
+                .help("Read a password from stdin and print its hash for the users file"),
+        )
         .arg(Arg::with_name("verbose").long("verbose").short("v"))
         .get_matches();
 
+    if matches.is_present("hash_password") {
+        let mut password = String::new();
+        io::stdin()
+            .read_line(&mut password)
+            .chain_err(|| "Failed to read password")?;
+        let password = password.trim_end_matches(&['\r', '\n'][..]);
+        println!("{}", auth::hash_password(password)?);
+        return Ok(());
+    }
+
     let wiki_root = matches.value_of("wiki_root").unwrap_or(".");
-    let editor = matches.value_of("editor").unwrap_or("subl");
+    let editor_command = matches.value_of("editor").unwrap_or(editor::DEFAULT_EDITOR);
     let show_web_page = !matches.is_present("skip_open");
-    let start_websocket = !matches.is_present("skip_websocket");
+    let auto_refresh = !matches.is_present("no_auto_refresh");
     let address = matches.value_of("address").unwrap_or("localhost");
+    let trusted_proxies = matches
+        .values_of("trusted_proxy")
+        .map(|proxies| proxies.map(str::parse).collect())
+        .unwrap_or_else(|| Ok(vec![]))
+        .chain_err(|| "--trusted-proxy needs an IP address")?;
+    let allowed_hosts: Vec<String> = matches
+        .values_of("allowed_host")
+        .map(|hosts| hosts.map(str::to_string).collect())
+        .unwrap_or_default();
     let verbose = matches.is_present("verbose");
+    let backup = matches.is_present("backup");
+    let wiki_config = wiki_config::load(Path::new(wiki_root))?;
+    let read_only = matches.is_present("read_only") || wiki_config.read_only;
+    let users = match matches.value_of("users") {
+        Some(path) => Some(auth::Users::load(Path::new(path))?),
+        None => None,
+    };
+
+    let debounce = match matches.value_of("debounce") {
+        Some(ms) => Duration::from_millis(
+            ms.parse::<u64>()
+                .chain_err(|| "--debounce needs a number of milliseconds")?,
+        ),
+        None => watcher::DEFAULT_DEBOUNCE,
+    };
 
     let port = if let Some(port_value) = matches.value_of("port") {
         port_value
@@ -359,10 +1633,10 @@ fn run() -> Result<()> {
     };
 
     let config = SiteConfig {
-        editor: editor.to_string(),
+        editor: editor::EditorCommand::new(editor_command),
         wiki_root: PathBuf::from(wiki_root),
-        socket_port: free_port::get_free_port()
-            .chain_err(|| "Couldn't find free port for web socket")?,
+        backup,
+        read_only,
     };
 
     if !free_port::is_port_available(address, port) {
@@ -384,9 +1658,42 @@ fn run() -> Result<()> {
 
     println!("Starting webserver at {}", &browser_path);
 
-    if start_websocket {
-        refresh_socket::listen(config.socket_port, wiki_root, verbose);
-    }
+    let index = Arc::new(index::SearchIndex::open(&config.wiki_root)?);
+    index::SearchIndex::save_periodically(&index);
+
+    let refresher = if auto_refresh {
+        Some(Arc::new(live_reload::Refresher::new()))
+    } else {
+        None
+    };
+    let watched_refresher = refresher.clone();
+
+    let watched_index = index.clone();
+    let watched_root = config.wiki_root.clone();
+    watcher::watch(wiki_root, debounce, verbose, move |changes| {
+        // Pages are told apart from new ones before the index learns of them.
+        let events = watched_refresher.as_ref().map(|_| {
+            let known = |page: &str| watched_index.document(page).is_some();
+            live_reload::batch_events(&watched_root, &changes, &known)
+        });
+
+        for change in &changes {
+            let updated = match change {
+                watcher::Change::Everything => watched_index.update_all(),
+                change => change
+                    .paths()
+                    .into_iter()
+                    .try_for_each(|path| watched_index.update_path(path)),
+            };
+            if let Err(e) = updated {
+                println!("Failed to update search index: {}", e);
+            }
+        }
+
+        if let (Some(refresher), Some(events)) = (&watched_refresher, events) {
+            refresher.send(events, verbose);
+        }
+    });
 
     let env = if verbose {
         Environment::Development
@@ -412,22 +1719,75 @@ fn run() -> Result<()> {
         ),
     );
 
+    if users.is_some() {
+        let secret_key_file = match matches.value_of("secret_key_file") {
+            Some(path) => PathBuf::from(path),
+            None => config.wiki_root.join(auth::DEFAULT_SECRET_KEY_FILE),
+        };
+        let secret_key = auth::load_secret_key(&secret_key_file)?;
+        rocket_config
+            .set_secret_key(secret_key)
+            .chain_err(|| "Invalid secret key")?;
+    }
+
+    let mut routes = routes![
+        index,
+        search,
+        show,
+        get_markdown,
+        nav,
+        live_events,
+        static_file,
+        forbidden_host,
+        login_page,
+        login,
+        logout,
+        audit_page,
+        api_search,
+        api_titles,
+        saved_searches
+    ];
+    if config.read_only {
+        routes.extend(routes![
+            read_only_edit,
+            read_only_edit_section,
+            read_only_delete,
+            read_only_trash,
+            read_only_replace,
+            read_only_post,
+        ]);
+    } else {
+        routes.extend(routes![
+            edit,
+            edit_post,
+            edit_section,
+            edit_section_post,
+            edit_editor,
+            preview,
+            upload,
+            delete,
+            delete_post,
+            trash_page,
+            trash_restore,
+            trash_purge,
+            replace_page,
+            replace_post,
+            saved_search_post,
+            saved_search_delete,
+        ]);
+    }
+
     rocket::custom(rocket_config)
-        .mount(
-            "/",
-            routes![
-                index,
-                search,
-                show,
-                get_markdown,
-                edit,
-                edit_post,
-                edit_editor,
-                static_file,
-            ],
-        )
+        .mount("/", routes)
+        .register(catchers![unauthorized])
         .attach(Template::fairing())
+        .attach(csrf::HostCheck)
         .manage(config)
+        .manage(auth::Auth { users })
+        .manage(index)
+        .manage(refresher)
+        .manage(csrf::AllowedHosts::new(address, port, &allowed_hosts))
+        .manage(audit::TrustedProxies(trusted_proxies))
         .launch();
 
     Ok(())
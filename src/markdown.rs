@@ -1,14 +1,27 @@
 use std::io;
 use std::path::{Path, PathBuf};
 
-use std::fs::File;
-use std::io::prelude::*;
+use regex::Regex;
+
+use crate::atomic_file;
+use crate::file_format::{self, FileFormat};
+use crate::links;
+use crate::search;
+use crate::section;
+
+/// Renders a fenced `search` block as HTML, given the words after `search`
+/// on its first line and the query inside it.
+pub type SearchBlockRenderer<'a> = &'a dyn Fn(&str, &str) -> String;
 
 pub struct MarkdownContext {
     pub page: String,
     pub title: String,
     pub file_path: PathBuf,
     pub file_content: Option<String>,
+    /// Encoding and line endings of the file, used when it is saved.
+    pub file_format: FileFormat,
+    /// Whether the rendered page gets edit links on its headings.
+    pub editable: bool,
 }
 
 impl MarkdownContext {
@@ -18,10 +31,11 @@ impl MarkdownContext {
 
         let path = wiki_root.join(&file_path);
 
-        let file_content = if path.exists() {
-            Some(get_file_content(&path)?)
+        let (file_content, file_format) = if path.exists() {
+            let (content, format) = file_format::read_text(&path)?;
+            (Some(content), format)
         } else {
-            None
+            (None, FileFormat::default())
         };
 
         Ok(MarkdownContext {
@@ -29,30 +43,147 @@ impl MarkdownContext {
             title: page_name,
             file_path: path,
             file_content: file_content,
+            file_format,
+            editable: true,
         })
     }
 
-    pub fn html(&self) -> Option<String> {
-        use pulldown_cmark::{html, Options, Parser};
+    /// `search` blocks are rendered by `searches`.
+    pub fn html(&self, searches: SearchBlockRenderer) -> Option<String> {
+        self.highlighted_html(&[], searches)
+    }
 
-        if let Some(ref file_content) = self.file_content {
-            let parser = Parser::new_ext(&file_content, Options::all());
-            let mut bfr = String::new();
-            html::push_html(&mut bfr, parser);
-            Some(bfr)
-        } else {
-            None
-        }
+    /// Renders the page with the text matching `highlights` marked, such as
+    /// the terms of the search that led to it.
+    pub fn highlighted_html(
+        &self,
+        highlights: &[&Regex],
+        searches: SearchBlockRenderer,
+    ) -> Option<String> {
+        self.file_content
+            .as_ref()
+            .map(|content| self.render(content, RenderMode::Page, highlights, searches))
+    }
+
+    /// Renders `content` as if it was the content of this page. Relative links
+    /// are made absolute, since the preview is shown on the edit page.
+    pub fn preview_html(&self, content: &str, searches: SearchBlockRenderer) -> String {
+        self.render(content, RenderMode::Preview, &[], searches)
+    }
+
+    fn render(
+        &self,
+        content: &str,
+        mode: RenderMode,
+        highlights: &[&Regex],
+        searches: SearchBlockRenderer,
+    ) -> String {
+        use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag};
+
+        let page = self.page.replace('\\', "/");
+        let sections = section::sections(content);
+        let mut heading_index = 0;
+        let mut heading_line = 1;
+        // Alt texts of images can't contain markup.
+        let mut image_depth = 0;
+        // The options and the query of the `search` block being read.
+        let mut search_block: Option<(String, String)> = None;
+        let parser = Parser::new_ext(content, Options::all())
+            .into_offset_iter()
+            .flat_map(|(event, range)| match event {
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info)))
+                    if is_search_block(info) =>
+                {
+                    let options = info.trim_start()["search".len()..].to_string();
+                    search_block = Some((options, String::new()));
+                    vec![]
+                }
+                Event::Text(ref text) if search_block.is_some() => {
+                    search_block.as_mut().unwrap().1.push_str(text);
+                    vec![]
+                }
+                Event::End(Tag::CodeBlock(_)) if search_block.is_some() => {
+                    let (options, query) = search_block.take().unwrap();
+                    vec![Event::Html(searches(&options, &query).into())]
+                }
+                Event::Start(Tag::Link(link_type, dest, title)) if mode == RenderMode::Preview => {
+                    let dest = links::absolute_url(&page, &dest);
+                    vec![Event::Start(Tag::Link(link_type, dest.into(), title))]
+                }
+                Event::Start(Tag::Image(link_type, dest, title)) if mode == RenderMode::Preview => {
+                    let dest = links::absolute_url(&page, &dest);
+                    vec![Event::Start(Tag::Image(link_type, dest.into(), title))]
+                }
+                Event::Start(Tag::Image(..)) => {
+                    image_depth += 1;
+                    vec![event]
+                }
+                Event::End(Tag::Image(..)) => {
+                    image_depth -= 1;
+                    vec![event]
+                }
+                Event::Text(text) if !highlights.is_empty() && image_depth == 0 => {
+                    let spans = search::match_spans(&text, highlights);
+                    let mut events = vec![];
+                    let mut position = 0;
+                    for (start, end) in spans {
+                        events.push(Event::Text(text[position..start].to_string().into()));
+                        events.push(Event::Html("<mark class=\"search-highlight\">".into()));
+                        events.push(Event::Text(text[start..end].to_string().into()));
+                        events.push(Event::Html("</mark>".into()));
+                        position = end;
+                    }
+                    events.push(Event::Text(text[position..].to_string().into()));
+                    events
+                }
+                Event::Start(Tag::Heading(level, _, classes)) if mode == RenderMode::Page => {
+                    heading_line = content[..range.start].matches('\n').count() + 1;
+                    let anchor = sections.get(heading_index).map(|s| s.anchor.as_str());
+                    vec![Event::Start(Tag::Heading(level, anchor, classes))]
+                }
+                Event::End(Tag::Heading(..)) if mode == RenderMode::Page => {
+                    let index = heading_index;
+                    heading_index += 1;
+                    if !self.editable {
+                        return vec![event];
+                    }
+                    let edit_links = format!(
+                        "<span class=\"section-edit\">\
+                         <a href=\"/edit_section/{page}?section={index}\">edit</a> | \
+                         <a href=\"/edit_editor/{page}?line={line}\" data-method=\"post\">editor</a></span>\n",
+                        page = links::page_url_path(&page),
+                        index = index,
+                        line = heading_line,
+                    );
+                    vec![event, Event::Html(edit_links.into())]
+                }
+                event => vec![event],
+            });
+
+        let mut bfr = String::new();
+        html::push_html(&mut bfr, parser);
+        bfr
     }
 
     pub fn exists(&self) -> bool {
         self.file_path.exists()
     }
+
+    /// Writes `content` to the markdown file, creating its folder if needed.
+    /// The file keeps the encoding and line endings it had when it was read.
+    pub fn save(&self, content: &str, keep_backup: bool) -> io::Result<()> {
+        let bytes = file_format::encode(content, &self.file_format);
+        atomic_file::write(&self.file_path, &bytes, keep_backup)
+    }
+}
+
+fn is_search_block(info: &str) -> bool {
+    info.split_whitespace().next() == Some("search")
 }
 
-fn get_file_content(file: &Path) -> io::Result<String> {
-    let mut file = File::open(file)?;
-    let mut file_content = String::new();
-    file.read_to_string(&mut file_content)?;
-    Ok(file_content)
+#[derive(PartialEq, Clone, Copy)]
+enum RenderMode {
+    /// The page itself, with edit links on every heading.
+    Page,
+    Preview,
 }
@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+
+use crate::errors::*;
+
+/// Page templates are markdown files in this folder, relative to the wiki
+/// root.
+pub const TEMPLATES_DIR: &str = "_templates";
+
+/// A folder can pick the template used for new pages inside it, and its
+/// subfolders, by naming it in this file.
+pub const DEFAULT_TEMPLATE_FILE: &str = ".template";
+
+/// Template names come from the url, so make sure they can't point outside
+/// the templates folder.
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        bail!("Invalid template name: {}", name);
+    }
+    Ok(())
+}
+
+/// Returns the names of all templates, sorted.
+pub fn list(wiki_root: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(wiki_root.join(TEMPLATES_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str()?;
+            file_name.strip_suffix(".md").map(|name| name.to_string())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Finds the default template for a new page, by looking for a
+/// `.template` file in the folder of the page and then in its parents.
+pub fn default_for(wiki_root: &Path, page: &str) -> Option<String> {
+    let page = page.replace('\\', "/");
+    let mut folder = Path::new(&page).parent();
+
+    while let Some(dir) = folder {
+        let file = wiki_root.join(dir).join(DEFAULT_TEMPLATE_FILE);
+        if let Ok(name) = fs::read_to_string(file) {
+            let name = name.trim();
+            if check_name(name).is_ok() {
+                return Some(name.to_string());
+            }
+        }
+        folder = dir.parent();
+    }
+
+    None
+}
+
+pub fn load(wiki_root: &Path, name: &str) -> Result<String> {
+    check_name(name)?;
+    let path = wiki_root.join(TEMPLATES_DIR).join(format!("{}.md", name));
+    fs::read_to_string(path).chain_err(|| format!("Failed to read template {}", name))
+}
+
+/// Replaces the `{{date}}`, `{{time}}`, `{{title}}`, `{{page}}` and
+/// `{{folder}}` placeholders of a template for a new page.
+pub fn fill(template: &str, page: &str, now: DateTime<Local>) -> String {
+    let page = page.replace('\\', "/");
+    let (folder, title) = match page.rfind('/') {
+        Some(index) => (&page[..index], &page[index + 1..]),
+        None => ("", page.as_str()),
+    };
+
+    template
+        .replace("{{date}}", &now.format("%Y-%m-%d").to_string())
+        .replace("{{time}}", &now.format("%H:%M").to_string())
+        .replace("{{title}}", title)
+        .replace("{{page}}", &page)
+        .replace("{{folder}}", folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn placeholders_are_filled() {
+        let now = Local.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap();
+        let template = "# {{title}}\n{{date}} {{time}} in {{folder}} ({{page}}) {{unknown}}";
+        assert_eq!(
+            fill(template, "meetings/standup", now),
+            "# standup\n2024-03-09 14:05 in meetings (meetings/standup) {{unknown}}"
+        );
+        assert_eq!(fill("{{folder}}/{{title}}", "home", now), "/home");
+    }
+
+    #[test]
+    fn template_names_are_checked() {
+        assert!(check_name("adr").is_ok());
+        assert!(check_name("../secret").is_err());
+        assert!(check_name(".template").is_err());
+        assert!(check_name("").is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use chrono::{Local, NaiveDate, TimeZone};
+use regex::{Regex, RegexBuilder};
+
+use crate::errors::*;
+use crate::index;
+
+/// Shown with errors, so that a bad query can be fixed.
+pub const SYNTAX_HELP: &str = "Words are searched for literally. Use \"quotes\" for phrases, \
+     re:pattern for a regex, case:yes to match case, path:folder, tag:name, \
+     modified:>2024-01-01, and AND, OR, NOT, -word and (parentheses) to combine them.";
+
+/// A part of a query that is matched against the text of a page.
+#[derive(Debug)]
+pub struct Term {
+    regex: Regex,
+    /// The words of a literal term, used to look it up in the search index.
+    /// Empty for a regex, which can't be looked up.
+    words: Vec<String>,
+    /// The text of a literal term, as it was written.
+    literal: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum DateComparison {
+    Before,
+    OnOrBefore,
+    On,
+    OnOrAfter,
+    After,
+}
+
+#[derive(Debug)]
+enum Filter {
+    /// The page is in this folder, or its path starts with this.
+    Path(String),
+    Tag(String),
+    Modified(DateComparison, NaiveDate),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Term(Term),
+    Filter(Filter),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+/// What a query is matched against.
+pub struct Page<'a> {
+    /// Such as `folder/page`.
+    pub path: &'a str,
+    pub tags: &'a [String],
+    pub modified: Option<NaiveDate>,
+    pub content: &'a str,
+}
+
+#[derive(Debug)]
+pub struct Query {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word { text: String, quoted: bool },
+}
+
+/// Splits a query into words and parentheses. Quotes keep spaces and
+/// operators in a word. A `(` only opens a group at the start of a word,
+/// and a `)` only closes one that is open, so `foo(` is a plain word.
+fn lex(query: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    let mut depth = 0;
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '(' {
+            chars.next();
+            depth += 1;
+            tokens.push(Token::Open);
+            continue;
+        }
+        if c == ')' && depth > 0 {
+            chars.next();
+            depth -= 1;
+            tokens.push(Token::Close);
+            continue;
+        }
+
+        let mut text = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        while let Some(&c) = chars.peek() {
+            if c == '"' {
+                in_quotes = !in_quotes;
+                quoted = true;
+            } else if !in_quotes && (c.is_whitespace() || (c == ')' && depth > 0)) {
+                break;
+            } else {
+                text.push(c);
+            }
+            chars.next();
+        }
+
+        if in_quotes {
+            bail!("Missing closing quote in {}", query);
+        }
+        tokens.push(Token::Word { text, quoted });
+    }
+
+    Ok(tokens)
+}
+
+fn is_operator(token: Option<&Token>, operator: &str) -> bool {
+    match token {
+        Some(Token::Word {
+            text,
+            quoted: false,
+        }) => text == operator,
+        _ => false,
+    }
+}
+
+fn parse_date(value: &str) -> Result<(DateComparison, NaiveDate)> {
+    let (comparison, date) = if let Some(date) = value.strip_prefix(">=") {
+        (DateComparison::OnOrAfter, date)
+    } else if let Some(date) = value.strip_prefix("<=") {
+        (DateComparison::OnOrBefore, date)
+    } else if let Some(date) = value.strip_prefix('>') {
+        (DateComparison::After, date)
+    } else if let Some(date) = value.strip_prefix('<') {
+        (DateComparison::Before, date)
+    } else {
+        (DateComparison::On, value.strip_prefix('=').unwrap_or(value))
+    };
+
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| {
+        Error::from(format!(
+            "Invalid date in modified:{}. Use a date like modified:>2024-01-31",
+            value
+        ))
+    })?;
+    Ok((comparison, date))
+}
+
+/// Queries longer than this are rejected.
+const MAX_QUERY_LENGTH: usize = 4096;
+/// Parentheses and NOTs nest at most this deep, which keeps the recursive
+/// parser and matcher from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    case_sensitive: bool,
+    /// Parentheses and NOTs around the current token.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get_mut(self.position).map(|token| {
+            std::mem::replace(
+                token,
+                Token::Word {
+                    text: String::new(),
+                    quoted: true,
+                },
+            )
+        });
+        self.position += 1;
+        token
+    }
+
+    fn term(&self, pattern: &str, literal: bool) -> Result<Term> {
+        let (source, words, literal) = if literal {
+            (
+                regex::escape(pattern),
+                index::tokenize(pattern).collect(),
+                Some(pattern.to_string()),
+            )
+        } else {
+            (pattern.to_string(), vec![], None)
+        };
+
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(!self.case_sensitive)
+            .multi_line(true)
+            .build()
+            .map_err(|e| Error::from(format!("Invalid regex re:{}: {}", pattern, e)))?;
+        Ok(Term {
+            regex,
+            words,
+            literal,
+        })
+    }
+
+    fn word(&self, text: String, quoted: bool) -> Result<Expr> {
+        if quoted && !text.contains(':') {
+            return Ok(Expr::Term(self.term(&text, true)?));
+        }
+
+        let (prefix, value) = match text.split_once(':') {
+            Some((prefix, value)) => (prefix, value),
+            None => return Ok(Expr::Term(self.term(&text, true)?)),
+        };
+
+        let needs_value = || -> Result<()> {
+            if value.is_empty() {
+                bail!("{}: needs a value, such as {}:something", prefix, prefix);
+            }
+            Ok(())
+        };
+
+        match prefix {
+            "re" => {
+                needs_value()?;
+                Ok(Expr::Term(self.term(value, false)?))
+            }
+            "path" => {
+                needs_value()?;
+                let path = value.trim_matches('/').replace('\\', "/").to_lowercase();
+                Ok(Expr::Filter(Filter::Path(path)))
+            }
+            "tag" => {
+                needs_value()?;
+                let tag = value.trim_start_matches('#').to_lowercase();
+                Ok(Expr::Filter(Filter::Tag(tag)))
+            }
+            "modified" => {
+                needs_value()?;
+                let (comparison, date) = parse_date(value)?;
+                Ok(Expr::Filter(Filter::Modified(comparison, date)))
+            }
+            // Anything else, like a url, is just text.
+            _ => Ok(Expr::Term(self.term(&text, true)?)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.and()?];
+        while is_operator(self.peek(), "OR") {
+            self.next();
+            if self.peek().is_none() || self.peek() == Some(&Token::Close) {
+                bail!("OR needs something to search for on both sides");
+            }
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut exprs = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) => break,
+                token if is_operator(token, "OR") => break,
+                token if is_operator(token, "AND") => {
+                    self.next();
+                    if exprs.is_empty() {
+                        bail!("AND needs something to search for on both sides");
+                    }
+                    continue;
+                }
+                _ => exprs.push(self.unary()?),
+            }
+        }
+
+        match exprs.len() {
+            0 => bail!("Expected something to search for"),
+            1 => Ok(exprs.pop().unwrap()),
+            _ => Ok(Expr::And(exprs)),
+        }
+    }
+
+    /// Parses the inside of a parenthesis or a NOT.
+    fn nested<F>(&mut self, parse: F) -> Result<Expr>
+    where
+        F: FnOnce(&mut Self) -> Result<Expr>,
+    {
+        if self.depth >= MAX_DEPTH {
+            bail!("Query is nested too deeply");
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if is_operator(self.peek(), "NOT") {
+            self.next();
+            if self.peek().is_none() {
+                bail!("NOT needs something to search for after it");
+            }
+            let expr = self.nested(Self::unary)?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.nested(Self::or)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => bail!("Missing closing parenthesis"),
+                }
+            }
+            Some(Token::Word {
+                text,
+                quoted: false,
+            }) if text.len() > 1 && text.starts_with('-') => {
+                let expr = self.word(text[1..].to_string(), false)?;
+                Ok(Expr::Not(Box::new(expr)))
+            }
+            Some(Token::Word { text, quoted }) => self.word(text, quoted),
+            Some(Token::Close) | None => bail!("Expected something to search for"),
+        }
+    }
+}
+
+/// Parses a search query. See `SYNTAX_HELP` for the syntax.
+pub fn parse(query: &str) -> Result<Query> {
+    if query.len() > MAX_QUERY_LENGTH {
+        bail!("Query is too long");
+    }
+    let mut tokens = lex(query)?;
+
+    // The case switch applies to the whole query, wherever it is.
+    let mut case_sensitive = false;
+    let mut case_error = None;
+    tokens.retain(|token| match token {
+        Token::Word {
+            text,
+            quoted: false,
+        } => match text.strip_prefix("case:") {
+            Some("yes") => {
+                case_sensitive = true;
+                false
+            }
+            Some("no") => {
+                case_sensitive = false;
+                false
+            }
+            Some(value) => {
+                case_error = Some(format!("Use case:yes or case:no, not case:{}", value));
+                false
+            }
+            None => true,
+        },
+        _ => true,
+    });
+    if let Some(error) = case_error {
+        bail!(error);
+    }
+
+    if tokens.is_empty() {
+        bail!("Enter something to search for");
+    }
+
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        case_sensitive,
+        depth: 0,
+    };
+    let expr = parser.or()?;
+    if parser.position < parser.tokens.len() {
+        bail!("Unexpected closing parenthesis");
+    }
+
+    Ok(Query {
+        source: query.to_string(),
+        expr,
+    })
+}
+
+/// The local date of a modification time from the search index.
+pub fn modified_date(millis: u64) -> Option<NaiveDate> {
+    Local
+        .timestamp_millis_opt(millis as i64)
+        .single()
+        .map(|time| time.date_naive())
+}
+
+impl Filter {
+    fn matches(&self, page: &Page) -> bool {
+        match self {
+            Filter::Path(path) => {
+                let page_path = page.path.to_lowercase();
+                page_path == *path
+                    || page_path.starts_with(&format!("{}/", path))
+                    || (!path.contains('/') && page_path.split('/').any(|part| part == path))
+            }
+            Filter::Tag(tag) => page.tags.iter().any(|t| t == tag),
+            Filter::Modified(comparison, date) => match page.modified {
+                Some(modified) => match comparison {
+                    DateComparison::Before => modified < *date,
+                    DateComparison::OnOrBefore => modified <= *date,
+                    DateComparison::On => modified == *date,
+                    DateComparison::OnOrAfter => modified >= *date,
+                    DateComparison::After => modified > *date,
+                },
+                None => false,
+            },
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, page: &Page) -> bool {
+        match self {
+            Expr::Term(term) => term.regex.is_match(page.content),
+            Expr::Filter(filter) => filter.matches(page),
+            Expr::Not(expr) => !expr.matches(page),
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(page)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(page)),
+        }
+    }
+
+    /// Words that every matching page contains.
+    fn required_words(&self, words: &mut Vec<String>) {
+        match self {
+            Expr::Term(term) => words.extend(term.words.iter().cloned()),
+            Expr::And(exprs) => {
+                for expr in exprs {
+                    expr.required_words(words);
+                }
+            }
+            Expr::Filter(_) | Expr::Not(_) | Expr::Or(_) => {}
+        }
+    }
+
+    /// Collects the text of literal terms, if they are all there is.
+    fn literal_text<'a>(&'a self, parts: &mut Vec<&'a str>) -> bool {
+        match self {
+            Expr::Term(Term {
+                literal: Some(text),
+                ..
+            }) => {
+                parts.push(text);
+                true
+            }
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.literal_text(parts)),
+            _ => false,
+        }
+    }
+
+    /// The terms that aren't negated, which are highlighted in the results.
+    fn positive_terms<'a>(&'a self, terms: &mut Vec<&'a Term>) {
+        match self {
+            Expr::Term(term) => terms.push(term),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                for expr in exprs {
+                    expr.positive_terms(terms);
+                }
+            }
+            Expr::Filter(_) | Expr::Not(_) => {}
+        }
+    }
+}
+
+impl Query {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn matches(&self, page: &Page) -> bool {
+        self.expr.matches(page)
+    }
+
+    /// Words that every matching page contains, so only the pages with all
+    /// of them in the search index need to be read.
+    pub fn required_words(&self) -> Vec<String> {
+        let mut words = vec![];
+        self.expr.required_words(&mut words);
+        words
+    }
+
+    /// The text of a query of only plain words, such as `meeting notes`,
+    /// which could be the name of a page.
+    pub fn text(&self) -> Option<String> {
+        let mut parts = vec![];
+        if self.expr.literal_text(&mut parts) {
+            Some(parts.join(" "))
+        } else {
+            None
+        }
+    }
+
+    /// All words searched for, used to rank the results.
+    pub fn words(&self) -> Vec<String> {
+        self.positive_terms()
+            .into_iter()
+            .flat_map(|term| term.words.iter().cloned())
+            .collect()
+    }
+
+    fn positive_terms(&self) -> Vec<&Term> {
+        let mut terms = vec![];
+        self.expr.positive_terms(&mut terms);
+        terms
+    }
+
+    /// The text to find and replace, and whether it is literal rather than a
+    /// regex. Filters and negated terms narrow down the pages to change.
+    pub fn replacement_term(&self) -> Result<(&Regex, bool)> {
+        match self.positive_terms()[..] {
+            [term] => Ok((&term.regex, term.literal.is_some())),
+            _ => bail!("Find and replace needs one word, \"phrase\" or re:pattern to replace"),
+        }
+    }
+
+    /// Regexes for the text to highlight in the results.
+    pub fn highlights(&self) -> Vec<&Regex> {
+        self.positive_terms()
+            .into_iter()
+            .map(|term| &term.regex)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page<'a>(path: &'a str, tags: &'a [String], content: &'a str) -> Page<'a> {
+        Page {
+            path,
+            tags,
+            modified: NaiveDate::from_ymd_opt(2024, 3, 1),
+            content,
+        }
+    }
+
+    fn matches(query: &str, content: &str) -> bool {
+        parse(query)
+            .unwrap()
+            .matches(&page("notes/a", &[], content))
+    }
+
+    #[test]
+    fn words_are_literal() {
+        assert!(matches("a+b", "x = a+b"));
+        assert!(!matches("a+b", "aab"));
+        assert!(matches("foo(", "call foo(1)"));
+        assert!(matches("FOO", "foo"));
+        assert!(!matches("FOO case:yes", "foo"));
+    }
+
+    #[test]
+    fn regex_terms() {
+        assert!(matches("re:fo+", "fooo"));
+        assert!(matches("re:^bar", "foo\nbar"));
+        assert!(matches(r#"re:"a (b|c)""#, "a c"));
+    }
+
+    #[test]
+    fn boolean_operators() {
+        assert!(matches("apple banana", "apple and banana"));
+        assert!(!matches("apple banana", "apple"));
+        assert!(matches("apple OR banana", "banana"));
+        assert!(matches("apple -banana", "apple"));
+        assert!(!matches("apple NOT banana", "apple banana"));
+        assert!(matches("(apple OR pear) AND banana", "pear banana"));
+        assert!(!matches("(apple OR pear) AND banana", "pear"));
+        assert!(matches("\"OR\"", "OR"));
+        assert!(matches("\"two words\"", "two words"));
+        assert!(!matches("\"two words\"", "words two"));
+    }
+
+    #[test]
+    fn filters() {
+        let tags = vec!["rust".to_string()];
+        let page = page("projects/wiki/todo", &tags, "text");
+        let check = |query: &str| parse(query).unwrap().matches(&page);
+
+        assert!(check("path:projects"));
+        assert!(check("path:projects/wiki"));
+        assert!(check("path:wiki"));
+        assert!(!check("path:proj"));
+        assert!(check("tag:#rust"));
+        assert!(!check("tag:go"));
+        assert!(check("modified:>2024-01-01"));
+        assert!(check("modified:2024-03-01"));
+        assert!(!check("modified:<2024-03-01"));
+        assert!(check("modified:<=2024-03-01 text"));
+    }
+
+    #[test]
+    fn index_words() {
+        let query = parse("apple (pear OR plum) -kiwi re:x+").unwrap();
+        assert_eq!(query.required_words(), vec!["apple"]);
+        assert_eq!(query.highlights().len(), 4);
+    }
+
+    #[test]
+    fn plain_text() {
+        let text = |query: &str| parse(query).unwrap().text();
+        assert_eq!(
+            text("Meeting \"new notes\""),
+            Some("Meeting new notes".to_string())
+        );
+        assert_eq!(text("a -b"), None);
+        assert_eq!(text("a tag:b"), None);
+        assert_eq!(text("re:a"), None);
+    }
+
+    #[test]
+    fn one_term_to_replace() {
+        let query = parse("\"old name\" path:services -draft").unwrap();
+        let (regex, literal) = query.replacement_term().unwrap();
+        assert!(regex.is_match("Old Name"));
+        assert!(literal);
+
+        assert!(!parse("re:a+").unwrap().replacement_term().unwrap().1);
+        assert!(parse("a OR b").unwrap().replacement_term().is_err());
+        assert!(parse("tag:x").unwrap().replacement_term().is_err());
+    }
+
+    #[test]
+    fn clear_errors() {
+        let error = |query: &str| parse(query).unwrap_err().to_string();
+        assert_eq!(error(""), "Enter something to search for");
+        assert_eq!(error("\"open"), "Missing closing quote in \"open");
+        assert!(error("re:(").starts_with("Invalid regex re:(:"));
+        assert!(error("modified:>yesterday").starts_with("Invalid date"));
+        assert_eq!(error("(a"), "Missing closing parenthesis");
+        assert_eq!(
+            error("a OR"),
+            "OR needs something to search for on both sides"
+        );
+        assert_eq!(error("tag:"), "tag: needs a value, such as tag:something");
+        assert_eq!(
+            error("case:maybe a"),
+            "Use case:yes or case:no, not case:maybe"
+        );
+        assert_eq!(error(&"(".repeat(2000)), "Query is nested too deeply");
+        assert_eq!(error(&"NOT ".repeat(1000)), "Query is nested too deeply");
+        assert_eq!(error(&"a ".repeat(5000)), "Query is too long");
+        assert!(parse(&format!("{}a{}", "(".repeat(60), ")".repeat(60))).is_ok());
+    }
+}
@@ -0,0 +1,395 @@
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::audit;
+use crate::errors::*;
+use crate::index;
+use crate::markdown::MarkdownContext;
+use crate::section;
+
+/// Unchanged lines shown around every change.
+const DIFF_CONTEXT: usize = 2;
+
+/// Replaces the matches of a regex, such as the term of a search query.
+pub struct Replacer<'a> {
+    regex: &'a Regex,
+    replacement: &'a str,
+    /// A literal replacement is used as is. Otherwise `$1` and `${name}` are
+    /// replaced by the groups of the match.
+    literal: bool,
+}
+
+/// A replaced match, as byte ranges in the old and the new content.
+struct Edit {
+    old: (usize, usize),
+    new: (usize, usize),
+}
+
+impl<'a> Replacer<'a> {
+    pub fn new(regex: &'a Regex, replacement: &'a str, literal: bool) -> Self {
+        Replacer {
+            regex,
+            replacement,
+            literal,
+        }
+    }
+
+    fn replace(&self, content: &str) -> (String, Vec<Edit>) {
+        let mut new = String::with_capacity(content.len());
+        let mut edits = vec![];
+        let mut last = 0;
+
+        for captures in self.regex.captures_iter(content) {
+            let m = captures.get(0).unwrap();
+            // Replacing empty matches would insert text all over the page.
+            if m.start() == m.end() {
+                continue;
+            }
+
+            new.push_str(&content[last..m.start()]);
+            let start = new.len();
+            if self.literal {
+                new.push_str(self.replacement);
+            } else {
+                captures.expand(self.replacement, &mut new);
+            }
+            edits.push(Edit {
+                old: (m.start(), m.end()),
+                new: (start, new.len()),
+            });
+            last = m.end();
+        }
+
+        new.push_str(&content[last..]);
+        (new, edits)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DiffLine {
+    /// `context`, `removed` or `added`.
+    pub kind: &'static str,
+    /// In the old file, except for added lines.
+    pub line_number: usize,
+    pub text: String,
+}
+
+/// The changes find and replace would make to a page.
+#[derive(Serialize)]
+pub struct FileChange {
+    pub page: String,
+    /// Hash of the content the preview was made from. The replacement is
+    /// only applied if the page hasn't changed since.
+    pub file_hash: String,
+    pub replacements: usize,
+    pub hunks: Vec<Vec<DiffLine>>,
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    starts
+}
+
+fn line_of(starts: &[usize], offset: usize) -> usize {
+    starts.partition_point(|&start| start <= offset) - 1
+}
+
+/// Lines of the old and the new content that replacements changed.
+struct Cluster {
+    old: (usize, usize),
+    new: (usize, usize),
+}
+
+/// Shows the lines that `edits` changed, grouped into hunks with a few
+/// unchanged lines around them.
+fn diff(old: &str, new: &str, edits: &[Edit]) -> Vec<Vec<DiffLine>> {
+    let old_lines: Vec<&str> = old.split('\n').map(|l| l.trim_end_matches('\r')).collect();
+    let new_lines: Vec<&str> = new.split('\n').map(|l| l.trim_end_matches('\r')).collect();
+    let old_count = old.lines().count();
+    let old_starts = line_starts(old);
+    let new_starts = line_starts(new);
+
+    // The rest of the line after a replacement is unchanged, so the lines
+    // from the start of a match to the end of it correspond in both.
+    let mut clusters: Vec<Cluster> = vec![];
+    for edit in edits {
+        let old_lines = (
+            line_of(&old_starts, edit.old.0),
+            line_of(&old_starts, edit.old.1),
+        );
+        let new_lines = (
+            line_of(&new_starts, edit.new.0),
+            line_of(&new_starts, edit.new.1),
+        );
+        match clusters.last_mut() {
+            Some(last) if old_lines.0 <= last.old.1 => {
+                last.old.1 = old_lines.1;
+                last.new.1 = new_lines.1;
+            }
+            _ => clusters.push(Cluster {
+                old: old_lines,
+                new: new_lines,
+            }),
+        }
+    }
+
+    let context = |line: usize| DiffLine {
+        kind: "context",
+        line_number: line + 1,
+        text: old_lines[line].to_string(),
+    };
+
+    let mut hunks: Vec<Vec<DiffLine>> = vec![];
+    let mut previous_end: Option<usize> = None;
+    for cluster in clusters {
+        match previous_end {
+            Some(end) if cluster.old.0 <= end + 1 + 2 * DIFF_CONTEXT => {
+                let hunk = hunks.last_mut().unwrap();
+                hunk.extend((end + 1..cluster.old.0).map(context));
+            }
+            _ => {
+                if let (Some(end), Some(hunk)) = (previous_end, hunks.last_mut()) {
+                    hunk.extend((end + 1..(end + 1 + DIFF_CONTEXT).min(old_count)).map(context));
+                }
+                let start = cluster.old.0.saturating_sub(DIFF_CONTEXT);
+                hunks.push((start..cluster.old.0).map(context).collect());
+            }
+        }
+
+        let hunk = hunks.last_mut().unwrap();
+        hunk.extend((cluster.old.0..=cluster.old.1).map(|line| DiffLine {
+            kind: "removed",
+            line_number: line + 1,
+            text: old_lines[line].to_string(),
+        }));
+        hunk.extend((cluster.new.0..=cluster.new.1).map(|line| DiffLine {
+            kind: "added",
+            line_number: line + 1,
+            text: new_lines[line].to_string(),
+        }));
+        previous_end = Some(cluster.old.1);
+    }
+
+    if let (Some(end), Some(hunk)) = (previous_end, hunks.last_mut()) {
+        hunk.extend((end + 1..(end + 1 + DIFF_CONTEXT).min(old_count)).map(context));
+    }
+    hunks
+}
+
+/// Shows what replacing in `pages` would change. Pages that wouldn't change
+/// are left out.
+pub fn preview(wiki_root: &Path, pages: &[String], replacer: &Replacer) -> Vec<FileChange> {
+    pages
+        .iter()
+        .filter_map(|page| {
+            let markdown = MarkdownContext::new(wiki_root, Path::new(page)).ok()?;
+            let content = markdown.file_content?;
+            let (new, edits) = replacer.replace(&content);
+            if new == content {
+                return None;
+            }
+            Some(FileChange {
+                page: page.clone(),
+                file_hash: section::content_hash(&content),
+                replacements: edits.len(),
+                hunks: diff(&content, &new, &edits),
+            })
+        })
+        .collect()
+}
+
+/// Pages to replace in come from a form, so they are checked to be pages
+/// inside the wiki: no absolute paths, `..` or hidden folders.
+pub fn check_page(page: &str) -> Result<()> {
+    match index::page_name(Path::new(&format!("{}.md", page))) {
+        Some(ref name) if name == page => Ok(()),
+        _ => bail!("{} is not a page of the wiki", page),
+    }
+}
+
+/// A page that find and replace changed.
+pub struct Applied {
+    /// The page as it was before the change.
+    pub markdown: MarkdownContext,
+    /// Hash of the file before the change, for the audit log.
+    pub old_hash: Option<String>,
+}
+
+/// Replaces in every page of `pages`, given with the hash of the content its
+/// preview was made from. Either every page is changed or none is: nothing
+/// is written if a page changed since the preview, and the pages already
+/// written are restored if writing one fails.
+pub fn apply(
+    wiki_root: &Path,
+    pages: &[(String, String)],
+    replacer: &Replacer,
+    keep_backup: bool,
+) -> Result<Vec<Applied>> {
+    let mut changes = vec![];
+    for (page, file_hash) in pages {
+        check_page(page)?;
+        let markdown = MarkdownContext::new(wiki_root, Path::new(page))
+            .chain_err(|| format!("Failed to read {}", page))?;
+        let content = match markdown.file_content {
+            Some(ref content) => content,
+            None => bail!("{} no longer exists", page),
+        };
+        if section::content_hash(content) != *file_hash {
+            bail!(
+                "{} was changed since the preview. Preview the replacement again.",
+                page
+            );
+        }
+
+        let (new, _) = replacer.replace(content);
+        if new != *content {
+            changes.push((markdown, new));
+        }
+    }
+
+    let mut applied: Vec<Applied> = vec![];
+    for (markdown, new) in changes {
+        let old_hash = audit::file_hash(&markdown.file_path);
+        if let Err(e) = markdown.save(&new, keep_backup) {
+            for done in &applied {
+                let old = done.markdown.file_content.as_deref().unwrap_or_default();
+                if let Err(e) = done.markdown.save(old, false) {
+                    println!("Failed to restore {}: {}", done.markdown.page, e);
+                }
+            }
+            return Err(e).chain_err(|| format!("Failed to save {}", markdown.page));
+        }
+        applied.push(Applied { markdown, old_hash });
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn replace(pattern: &str, replacement: &str, literal: bool, content: &str) -> String {
+        let regex = Regex::new(pattern).unwrap();
+        Replacer::new(&regex, replacement, literal)
+            .replace(content)
+            .0
+    }
+
+    #[test]
+    fn replaces_literally_or_with_groups() {
+        assert_eq!(
+            replace("old", "$1 new", true, "an old one"),
+            "an $1 new one"
+        );
+        assert_eq!(
+            replace(r"(\w+)-svc", "${1}-service", false, "auth-svc and db-svc"),
+            "auth-service and db-service"
+        );
+        assert_eq!(replace("x*", "y", false, "ab"), "ab");
+    }
+
+    #[test]
+    fn diff_shows_changed_lines_with_context() {
+        let old = "1\n2\n3 old\n4\n5\n6\n7\n8\n9 old old\n10\n";
+        let regex = Regex::new("old").unwrap();
+        let replacer = Replacer::new(&regex, "new", true);
+        let (new, edits) = replacer.replace(old);
+        let hunks = diff(old, &new, &edits);
+
+        let lines = |hunk: &[DiffLine]| -> Vec<String> {
+            hunk.iter()
+                .map(|line| format!("{} {} {}", line.kind, line.line_number, line.text))
+                .collect()
+        };
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(
+            lines(&hunks[0]),
+            vec![
+                "context 1 1",
+                "context 2 2",
+                "removed 3 3 old",
+                "added 3 3 new",
+                "context 4 4",
+                "context 5 5",
+            ]
+        );
+        assert_eq!(
+            lines(&hunks[1]),
+            vec![
+                "context 7 7",
+                "context 8 8",
+                "removed 9 9 old old",
+                "added 9 9 new new",
+                "context 10 10",
+            ]
+        );
+    }
+
+    #[test]
+    fn nearby_changes_share_a_hunk() {
+        let old = "a\nold\nb\nold\nc\n";
+        let regex = Regex::new("old").unwrap();
+        let replacer = Replacer::new(&regex, "new", true);
+        let (new, edits) = replacer.replace(old);
+        assert_eq!(diff(old, &new, &edits).len(), 1);
+    }
+
+    #[test]
+    fn apply_changes_nothing_if_a_page_changed() {
+        let root = env::temp_dir().join(format!("simplewiki-replace-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.md"), "old a\n").unwrap();
+        fs::write(root.join("b.md"), "old b\n").unwrap();
+
+        let regex = Regex::new("old").unwrap();
+        let replacer = Replacer::new(&regex, "new", true);
+        let pages = vec!["a".to_string(), "b".to_string()];
+        let previews = preview(&root, &pages, &replacer);
+        assert_eq!(previews.len(), 2);
+
+        let mut selected: Vec<(String, String)> = previews
+            .iter()
+            .map(|change| (change.page.clone(), change.file_hash.clone()))
+            .collect();
+        selected[1].1 = "stale".to_string();
+        assert!(apply(&root, &selected, &replacer, false).is_err());
+        assert_eq!(fs::read_to_string(root.join("a.md")).unwrap(), "old a\n");
+
+        selected[1].1 = previews[1].file_hash.clone();
+        assert_eq!(apply(&root, &selected, &replacer, false).unwrap().len(), 2);
+        assert_eq!(fs::read_to_string(root.join("a.md")).unwrap(), "new a\n");
+        assert_eq!(fs::read_to_string(root.join("b.md")).unwrap(), "new b\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn only_pages_inside_the_wiki_are_changed() {
+        assert!(check_page("folder/page").is_ok());
+        assert!(check_page("my page").is_ok());
+        assert!(check_page("/etc/foo").is_err());
+        assert!(check_page("../other/README").is_err());
+        assert!(check_page("folder/../../x").is_err());
+        assert!(check_page(".git/config").is_err());
+        assert!(check_page("./page").is_err());
+        assert!(check_page("").is_err());
+
+        let root = env::temp_dir().join(format!("simplewiki-outside-{}", process::id()));
+        let outside = root.join("outside.md");
+        fs::create_dir_all(root.join("wiki")).unwrap();
+        fs::write(&outside, "old\n").unwrap();
+
+        let regex = Regex::new("old").unwrap();
+        let replacer = Replacer::new(&regex, "new", true);
+        let pages = vec![("../outside".to_string(), section::content_hash("old\n"))];
+        assert!(apply(&root.join("wiki"), &pages, &replacer, false).is_err());
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "old\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rocket::http::uri::Uri;
+use tera::escape_html;
+
+use crate::atomic_file;
+use crate::errors::*;
+use crate::index::Document;
+use crate::links;
+
+/// Saved searches by name, relative to the wiki root.
+const SAVED_SEARCHES_FILE: &str = ".simplewiki/saved_searches.json";
+
+/// Pages listed by a `search` block, unless it sets `limit=`.
+pub const DEFAULT_BLOCK_LIMIT: usize = 20;
+pub const MAX_BLOCK_LIMIT: usize = 200;
+
+/// Serializes the read-modify-write of the saved searches file.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SavedSearch {
+    pub name: String,
+    pub query: String,
+    pub saved_by: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+fn read(wiki_root: &Path) -> BTreeMap<String, SavedSearch> {
+    fs::read_to_string(wiki_root.join(SAVED_SEARCHES_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write(wiki_root: &Path, searches: &BTreeMap<String, SavedSearch>) -> Result<()> {
+    let content = serde_json::to_string_pretty(searches).chain_err(|| "Failed to serialize")?;
+    atomic_file::write(
+        &wiki_root.join(SAVED_SEARCHES_FILE),
+        content.as_bytes(),
+        false,
+    )
+    .chain_err(|| "Failed to write saved searches file")
+}
+
+/// Saved searches, ordered by name.
+pub fn list(wiki_root: &Path) -> Vec<SavedSearch> {
+    read(wiki_root).into_values().collect()
+}
+
+pub fn get(wiki_root: &Path, name: &str) -> Option<SavedSearch> {
+    read(wiki_root).remove(name)
+}
+
+/// Saves `query` under `name`, replacing any search with the same name.
+pub fn save(wiki_root: &Path, name: &str, query: &str, user: Option<&str>) -> Result<()> {
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        bail!("Name the search with a single word, such as team-x-todos");
+    }
+
+    let _lock = LOCK.lock().unwrap();
+    let mut searches = read(wiki_root);
+    searches.insert(
+        name.to_string(),
+        SavedSearch {
+            name: name.to_string(),
+            query: query.to_string(),
+            saved_by: user.map(str::to_string),
+            time: Utc::now(),
+        },
+    );
+    write(wiki_root, &searches)
+}
+
+pub fn delete(wiki_root: &Path, name: &str) -> Result<()> {
+    let _lock = LOCK.lock().unwrap();
+    let mut searches = read(wiki_root);
+    if searches.remove(name).is_none() {
+        bail!("There is no saved search named {}", name);
+    }
+    write(wiki_root, &searches)
+}
+
+/// A fenced `search` block in a page, such as
+///
+/// ````text
+/// ```search limit=10
+/// "TODO(team-x)"
+/// ```
+/// ````
+///
+/// The block contains a query, or `saved:name` for a saved search.
+#[derive(Debug, PartialEq)]
+pub struct SearchBlock {
+    pub query: String,
+    pub limit: usize,
+}
+
+/// Reads a `search` block, given the words after `search` on its first line
+/// and what's inside it.
+pub fn parse_block(wiki_root: &Path, options: &str, body: &str) -> Result<SearchBlock> {
+    let mut limit = DEFAULT_BLOCK_LIMIT;
+    for option in options.split_whitespace() {
+        match option.split_once('=') {
+            Some(("limit", value)) => {
+                limit = value
+                    .parse()
+                    .ok()
+                    .filter(|&limit| limit > 0)
+                    .chain_err(|| format!("limit= needs a number, not {}", value))?;
+            }
+            _ => bail!("Unknown option {}. Use limit=number", option),
+        }
+    }
+
+    let body = body.trim();
+    let query = match body.strip_prefix("saved:") {
+        Some(name) => match get(wiki_root, name.trim()) {
+            Some(search) => search.query,
+            None => bail!("There is no saved search named {}", name.trim()),
+        },
+        None => body.to_string(),
+    };
+
+    Ok(SearchBlock {
+        query,
+        limit: limit.min(MAX_BLOCK_LIMIT),
+    })
+}
+
+/// Lists the pages a `search` block found, with their first headings.
+/// `total` is the number of pages found, of which only the first are given.
+pub fn block_html(block: &SearchBlock, pages: &[(&str, &Document)], total: usize) -> String {
+    let mut html = String::from("<div class=\"search-block\">\n");
+    if pages.is_empty() {
+        html.push_str("<p class=\"text-muted\">No pages found.</p>\n");
+    } else {
+        html.push_str("<ul>\n");
+        for (page, document) in pages {
+            html.push_str(&format!(
+                "<li><a href=\"/{}\">{}</a>",
+                escape_html(&links::page_url_path(page)),
+                escape_html(page)
+            ));
+            if let Some(heading) = document.headings.first() {
+                html.push_str(&format!(
+                    " <span class=\"text-muted\">{}</span>",
+                    escape_html(heading)
+                ));
+            }
+            html.push_str("</li>\n");
+        }
+        html.push_str("</ul>\n");
+    }
+
+    let link = format!(
+        "/search?pattern={}",
+        escape_html(&Uri::percent_encode(&block.query))
+    );
+    if total > pages.len() {
+        html.push_str(&format!(
+            "<p><a href=\"{}\">Show all {} pages</a></p>\n",
+            link, total
+        ));
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+/// Shown in place of a `search` block that can't be run.
+pub fn block_error_html(error: &Error) -> String {
+    format!(
+        "<div class=\"search-block alert alert-danger\">{}</div>\n",
+        escape_html(&error.to_string())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn block_options_and_saved_queries() {
+        let root = env::temp_dir().join(format!("simplewiki-saved-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        assert_eq!(
+            parse_block(&root, "", " \"TODO(team-x)\"\n").unwrap(),
+            SearchBlock {
+                query: "\"TODO(team-x)\"".to_string(),
+                limit: DEFAULT_BLOCK_LIMIT,
+            }
+        );
+        assert_eq!(parse_block(&root, "limit=5", "foo").unwrap().limit, 5);
+        assert_eq!(
+            parse_block(&root, "limit=100000", "foo").unwrap().limit,
+            MAX_BLOCK_LIMIT
+        );
+        assert!(parse_block(&root, "limit=0", "foo").is_err());
+        assert!(parse_block(&root, "sort=name", "foo").is_err());
+        assert!(parse_block(&root, "", "saved:todos").is_err());
+
+        save(&root, "todos", "TODO tag:team-x", Some("alice")).unwrap();
+        assert_eq!(
+            parse_block(&root, "", "saved:todos\n").unwrap().query,
+            "TODO tag:team-x"
+        );
+        assert!(save(&root, "two words", "foo", None).is_err());
+
+        fs::write(root.join("50% off #1.md"), "sale").unwrap();
+        let document = crate::index::SearchIndex::open(&root)
+            .unwrap()
+            .document("50% off #1")
+            .unwrap();
+        let block = parse_block(&root, "", "sale").unwrap();
+        assert!(block_html(&block, &[("50% off #1", &document)], 1)
+            .contains("<a href=\"/50%25%20off%20%231\">50% off #1</a>"));
+
+        delete(&root, "todos").unwrap();
+        assert!(list(&root).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,24 +1,230 @@
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tera::escape_html;
 
-use walkdir::{DirEntry, WalkDir};
+use rayon::prelude::*;
+
+use walkdir::DirEntry;
 
 use stopwatch::Stopwatch;
 
-use regex;
+use regex::Regex;
 
 use crate::errors::*;
+use crate::file_format;
+use crate::index::{self, SearchIndex};
+use crate::query::{self, Query};
+use crate::section;
+
+/// Lines shown before and after a match, unless asked for otherwise.
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// More context than this is never shown, to keep results readable.
+const MAX_CONTEXT: usize = 20;
+
+/// How many pages are shown above the results for matching by name.
+const MAX_TITLE_MATCHES: usize = 10;
+
+/// Matching pages shown at a time, unless asked for otherwise.
+pub const DEFAULT_LIMIT: usize = 50;
 
-const CONTEXT: usize = 3;
+/// More pages than this are never shown at a time, to keep pagination.
+const MAX_LIMIT: usize = 200;
+
+/// Files bigger than this aren't searched.
+const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Only the start of a file is checked for being binary.
+const BINARY_CHECK_LENGTH: usize = 8192;
 
 #[derive(Serialize)]
 pub struct SearchResult {
     pub pattern: String,
     pub matches: Vec<SearchFileMatch>,
     pub elapsed: i64,
+    /// How many of the matching pages are in each folder.
+    pub folders: Vec<Facet>,
+    /// How many of the matching pages have each tag.
+    pub tags: Vec<Facet>,
+    /// Pages whose name or title match, best first.
+    pub titles: Vec<TitleMatch>,
+    /// The page the query names, when it doesn't exist.
+    pub new_page: Option<String>,
+    /// How many pages matched. Only some of them are in `matches`.
+    pub total: usize,
+    /// The offset to show more results from, if there are more.
+    pub next_offset: Option<usize>,
+    /// Whether the search ran out of time before every page was searched,
+    /// so that some matches may be missing.
+    pub truncated: bool,
+    /// Pages that weren't searched, because they are too big or binary.
+    pub skipped: usize,
+}
+
+/// How a search is done and its results shown.
+pub struct SearchOptions {
+    /// Lines shown before and after every match.
+    pub context: usize,
+    /// How many of the matching pages to skip, to show more results.
+    pub offset: usize,
+    /// How many matching pages to show.
+    pub limit: usize,
+    /// Searching stops after this long, and the pages found so far are shown.
+    pub time_budget: Duration,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            context: DEFAULT_CONTEXT,
+            offset: 0,
+            limit: DEFAULT_LIMIT,
+            time_budget: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What searching a single page found.
+enum PageOutcome {
+    Match(index::Document),
+    NoMatch,
+    Skipped,
+    OutOfTime,
+}
+
+/// NUL bytes aren't in text files, except for UTF-16, which starts with a
+/// byte order mark.
+fn is_binary(bytes: &[u8]) -> bool {
+    let utf16 = bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]);
+    !utf16 && bytes.iter().take(BINARY_CHECK_LENGTH).any(|&b| b == 0)
+}
+
+/// Reads a page to search it. `None` if it is too big or binary.
+fn read_searchable(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.len() > MAX_FILE_SIZE {
+        return None;
+    }
+    let bytes = fs::read(path).ok()?;
+    if is_binary(&bytes) {
+        return None;
+    }
+    Some(file_format::decode(&bytes).0)
+}
+
+#[derive(Serialize)]
+pub struct TitleMatch {
+    pub page: String,
+    pub url: String,
+    /// The front-matter title of the page, or else its first heading.
+    pub heading: Option<String>,
+    pub score: f32,
+}
+
+/// Scores how well `text` matches the words of a query, ignoring case. Text
+/// containing every word scores above 1, more if the words make up more of
+/// it. Otherwise the letters of the words have to be in the text in order,
+/// scoring higher the closer together they are.
+fn fuzzy_score(words: &[String], text: &str) -> Option<f32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    if words.is_empty() || text.is_empty() {
+        return None;
+    }
+
+    let lowercase: String = text.iter().collect();
+    if words.iter().all(|word| lowercase.contains(word.as_str())) {
+        let matched: usize = words.iter().map(|word| word.chars().count()).sum();
+        return Some(1.0 + (matched as f32 / text.len() as f32).min(1.0));
+    }
+
+    let letters: Vec<char> = words.concat().chars().collect();
+    let mut found = 0;
+    let mut start = 0;
+    let mut end = 0;
+    for (i, &c) in text.iter().enumerate() {
+        if found < letters.len() && c == letters[found] {
+            if found == 0 {
+                start = i;
+            }
+            found += 1;
+            end = i + 1;
+        }
+    }
+
+    if found < letters.len() {
+        return None;
+    }
+    Some(letters.len() as f32 / (end - start) as f32)
+}
+
+/// Matches words against the names of the pages and their titles, from the
+/// front matter or else the first heading, and returns the `limit` best
+/// matches that `include` accepts.
+pub fn search_titles<F, G>(
+    words: &[String],
+    limit: usize,
+    directory: &str,
+    url: &F,
+    include: &G,
+    index: &SearchIndex,
+) -> Result<Vec<TitleMatch>>
+where
+    F: Fn(&Path, &Path) -> Result<String>,
+    G: Fn(&Path) -> bool,
+{
+    let mut titles = vec![];
+    for (page, heading) in index.titles() {
+        let score = fuzzy_score(words, &page)
+            .into_iter()
+            .chain(heading.as_ref().and_then(|h| fuzzy_score(words, h)))
+            .fold(None, |best: Option<f32>, score| {
+                Some(best.map_or(score, |best| best.max(score)))
+            });
+        if let Some(score) = score {
+            titles.push((page, heading, score));
+        }
+    }
+    titles.sort_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+
+    let mut matches = vec![];
+    for (page, heading, score) in titles {
+        if matches.len() == limit {
+            break;
+        }
+        if !include(&PathBuf::from(format!("{}.md", page))) {
+            continue;
+        }
+        let file_path = index::page_file(Path::new(directory), &page);
+        matches.push(TitleMatch {
+            url: url(Path::new(directory), &file_path)?,
+            page,
+            heading,
+            score,
+        });
+    }
+    Ok(matches)
+}
+
+#[derive(Serialize)]
+pub struct Facet {
+    pub name: String,
+    pub count: usize,
+}
+
+fn facets<I: Iterator<Item = String>>(names: I) -> Vec<Facet> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for name in names {
+        *counts.entry(name).or_insert(0) += 1;
+    }
+
+    let mut facets: Vec<Facet> = counts
+        .into_iter()
+        .map(|(name, count)| Facet { name, count })
+        .collect();
+    facets.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    facets
 }
 
 #[derive(Serialize)]
@@ -27,10 +233,18 @@ pub struct SearchFileMatch {
     pub file_path: PathBuf,
     pub url: String,
     pub contexts: Vec<SearchFileMatchContext>,
+    pub score: f32,
+    /// The page, relative to the wiki root.
+    #[serde(skip)]
+    pub page: String,
 }
 
 #[derive(Serialize)]
 pub struct SearchFileMatchContext {
+    /// Line number of the matching line.
+    pub line_number: i32,
+    /// Anchor of the heading the match is under.
+    pub anchor: Option<String>,
     pub contexts: Vec<SearchMatchContext>,
     pub html: String,
 }
@@ -96,9 +310,16 @@ impl ToHtml for [SearchMatchContext] {
     }
 }
 
-fn is_markdown(entry: &DirEntry) -> bool {
+/// Used to filter `WalkDir` down to markdown pages. Hidden folders, such as
+/// `.git` and `.simplewiki`, are skipped.
+pub fn is_markdown(entry: &DirEntry) -> bool {
     if entry.file_type().is_dir() {
-        true
+        let is_hidden = entry
+            .file_name()
+            .to_str()
+            .map(|s| s.starts_with('.'))
+            .unwrap_or(false);
+        entry.depth() == 0 || !is_hidden
     } else {
         entry
             .file_name()
@@ -108,118 +329,307 @@ fn is_markdown(entry: &DirEntry) -> bool {
     }
 }
 
-pub fn search<F>(pattern: &str, directory: &str, url: F) -> Result<SearchResult>
+/// The pages matching a query, best first.
+pub struct FoundPages {
+    /// Pages with their scores.
+    pub pages: Vec<(String, f32)>,
+    /// The index entries of `pages`.
+    pub documents: Vec<index::Document>,
+    /// Pages that weren't searched, because they are too big or binary.
+    pub skipped: usize,
+    /// Whether time ran out before every page was searched.
+    pub truncated: bool,
+}
+
+/// Finds the pages in `directory` that match `query` and that `include`
+/// accepts, ranked by `index`. Pages are read in parallel, until
+/// `time_budget` runs out.
+///
+/// Words that every match must contain are looked up in the index, so only
+/// the pages containing them are read. Otherwise every page is read.
+pub fn find_pages<G>(
+    query: &Query,
+    directory: &str,
+    include: G,
+    index: &SearchIndex,
+    time_budget: Duration,
+) -> FoundPages
+where
+    G: Fn(&Path) -> bool,
+{
+    let deadline = Instant::now() + time_budget;
+    let required_words = query.required_words();
+    let (words, require_all) = if required_words.is_empty() {
+        (query.words(), false)
+    } else {
+        (required_words, true)
+    };
+
+    let candidates: Vec<(String, f32)> = index
+        .rank(&words, require_all)
+        .into_iter()
+        .filter(|(page, _)| include(&PathBuf::from(format!("{}.md", page))))
+        .collect();
+
+    let outcomes: Vec<PageOutcome> = candidates
+        .par_iter()
+        .map(|(page, _)| {
+            if Instant::now() > deadline {
+                return PageOutcome::OutOfTime;
+            }
+            let document = match index.document(page) {
+                Some(document) => document,
+                None => return PageOutcome::NoMatch,
+            };
+            let file_path = index::page_file(Path::new(directory), page);
+            let content = match read_searchable(&file_path) {
+                Some(content) => content,
+                None => return PageOutcome::Skipped,
+            };
+
+            let is_match = query.matches(&query::Page {
+                path: page,
+                tags: &document.tags,
+                modified: query::modified_date(document.modified),
+                content: &content,
+            });
+            if is_match {
+                PageOutcome::Match(document)
+            } else {
+                PageOutcome::NoMatch
+            }
+        })
+        .collect();
+
+    let mut found = FoundPages {
+        pages: vec![],
+        documents: vec![],
+        skipped: 0,
+        truncated: false,
+    };
+    for ((page, score), outcome) in candidates.into_iter().zip(outcomes) {
+        match outcome {
+            PageOutcome::Match(document) => {
+                found.pages.push((page, score));
+                found.documents.push(document);
+            }
+            PageOutcome::NoMatch => {}
+            PageOutcome::Skipped => found.skipped += 1,
+            PageOutcome::OutOfTime => found.truncated = true,
+        }
+    }
+    found
+}
+
+/// Searches the pages in `directory`, which is the root of `index`. Only the
+/// files that `include` accepts, given their path relative to `directory`,
+/// are searched. Results are ranked by the index, best first.
+/// See `find_pages` for how pages are found.
+pub fn search<F, G>(
+    query: &Query,
+    options: &SearchOptions,
+    directory: &str,
+    url: F,
+    include: G,
+    index: &SearchIndex,
+) -> Result<SearchResult>
 where
     F: Fn(&Path, &Path) -> Result<String>,
+    G: Fn(&Path) -> bool,
 {
     let sw = Stopwatch::start_new();
     let mut result = SearchResult {
-        pattern: pattern.to_string(),
+        pattern: query.source().to_string(),
         matches: vec![],
         elapsed: 0,
+        folders: vec![],
+        tags: vec![],
+        titles: search_titles(
+            &query.words(),
+            MAX_TITLE_MATCHES,
+            directory,
+            &url,
+            &include,
+            index,
+        )?,
+        new_page: None,
+        total: 0,
+        next_offset: None,
+        truncated: false,
+        skipped: 0,
     };
 
-    let walker = WalkDir::new(directory).into_iter();
-    for entry in walker.filter_entry(|e| is_markdown(e)) {
-        let entry: DirEntry =
-            entry.chain_err(|| "unable to unwrap entry. Directory traversel error?")?;
-
-        if entry.metadata().map(|e| e.is_dir()).unwrap_or(false) {
-            continue;
+    if let Some(text) = query.text() {
+        let page = text.trim().trim_matches('/');
+        // Pages the user can't read are left out, so that offering to create
+        // a page doesn't tell whether they exist.
+        let exists = index.titles().iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case(page) && include(&PathBuf::from(format!("{}.md", name)))
+        });
+        if !page.is_empty() && !exists {
+            result.new_page = Some(page.to_string());
         }
+    }
 
-        if let Ok(search_file_match) = search_file(entry, pattern, directory, &url) {
-            result.matches.push(search_file_match);
-        }
+    let highlights = query.highlights();
+    let context = options.context.min(MAX_CONTEXT);
+
+    let found = find_pages(query, directory, include, index, options.time_budget);
+    let matching = found.pages;
+    let documents = found.documents;
+    result.skipped = found.skipped;
+    result.truncated = found.truncated;
+
+    result.total = matching.len();
+    let limit = options.limit.clamp(1, MAX_LIMIT);
+    let next_offset = options.offset.saturating_add(limit);
+    if next_offset < result.total {
+        result.next_offset = Some(next_offset);
     }
 
+    // Only the pages shown need their matching lines.
+    for (page, score) in matching.iter().skip(options.offset).take(limit) {
+        let file_path = index::page_file(Path::new(directory), page);
+        let content = match read_searchable(&file_path) {
+            Some(content) => content,
+            None => continue,
+        };
+        let mut search_file_match =
+            search_file(&file_path, &content, &highlights, context, directory, &url)?;
+        search_file_match.score = *score;
+        search_file_match.page = page.clone();
+        result.matches.push(search_file_match);
+    }
+
+    result.folders = facets(matching.iter().map(|(page, _)| match page.rfind('/') {
+        Some(end) => page[..end].to_string(),
+        None => "/".to_string(),
+    }));
+    result.tags = facets(documents.into_iter().flat_map(|document| document.tags));
+
     result.elapsed = sw.elapsed_ms();
 
     Ok(result)
 }
 
+/// The spans of `line` matched by any of `highlights`, in order. Overlapping
+/// and touching spans are merged into one.
+pub fn match_spans(line: &str, highlights: &[&Regex]) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = highlights
+        .iter()
+        .flat_map(|regex| regex.find_iter(line))
+        .filter(|m| m.end() > m.start())
+        .map(|m| (m.start(), m.end()))
+        .collect();
+    spans.sort();
+
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Splits a line into text and highlighted matches.
+fn highlight(line: &str, spans: &[(usize, usize)]) -> Vec<SearchMatchText> {
+    let mut texts = vec![];
+    let mut position = 0;
+    for &(start, end) in spans {
+        if start > position {
+            texts.push(SearchMatchText::Text(line[position..start].into()));
+        }
+        texts.push(SearchMatchText::Match(line[start..end].into()));
+        position = end;
+    }
+    if position < line.len() || texts.is_empty() {
+        texts.push(SearchMatchText::Text(line[position..].into()));
+    }
+    texts
+}
+
+/// The ranges of lines to show around the matching lines, which are sorted.
+/// Windows that overlap or touch are merged, so no line is shown twice.
+fn context_windows(
+    matching_lines: &[usize],
+    context: usize,
+    line_count: usize,
+) -> Vec<(usize, usize)> {
+    let mut windows: Vec<(usize, usize)> = vec![];
+    for &line in matching_lines {
+        let start = line.saturating_sub(context);
+        let end = (line + context + 1).min(line_count);
+        match windows.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => windows.push((start, end)),
+        }
+    }
+    windows
+}
+
+/// Shows the lines of a matching page that match any of `highlights`, with
+/// `context` lines around them. A page that only matched a filter has no
+/// lines to show.
 fn search_file<F>(
-    entry: DirEntry,
-    pattern: &str,
+    file_path: &Path,
+    content: &str,
+    highlights: &[&Regex],
+    context: usize,
     directory: &str,
     url: &F,
 ) -> Result<SearchFileMatch>
 where
     F: Fn(&Path, &Path) -> Result<String>,
 {
-    let pattern = &format!("(?i){}", pattern);
-    let pattern_re = regex::Regex::new(pattern).chain_err(|| "Invalid pattern")?;
-    let pattern_specific_re =
-        regex::Regex::new(&format!("^(?P<pre>.*)(?P<match>{})(?P<post>.*)$", pattern)).unwrap();
-
-    let f = File::open(entry.path()).chain_err(|| "Failed to open file")?;
-
-    let file = BufReader::new(&f);
-
-    let lines: Vec<String> = file.lines().filter_map(|e| e.ok()).collect();
+    let lines: Vec<&str> = content.lines().collect();
+    let spans: Vec<Vec<(usize, usize)>> = lines
+        .iter()
+        .map(|line| match_spans(line, highlights))
+        .collect();
+    let matching_lines: Vec<usize> = (0..lines.len()).filter(|&i| !spans[i].is_empty()).collect();
+
+    // Line index of every heading, and its anchor.
+    let headings: Vec<(usize, String)> = section::sections(content)
+        .into_iter()
+        .map(|section| {
+            let line = content[..section.start].matches('\n').count();
+            (line, section.anchor)
+        })
+        .collect();
 
     let directory_path = Path::new(directory);
-    let url = url(&directory_path, entry.path())?;
+    let url = url(directory_path, file_path)?;
     let mut file_match = SearchFileMatch {
-        file_name: entry.path().as_os_str().to_str().unwrap().to_string(),
-        file_path: entry.path().into(),
+        file_name: file_path.as_os_str().to_str().unwrap().to_string(),
+        file_path: file_path.into(),
         url: url,
         contexts: vec![],
+        score: 0.0,
+        page: String::new(),
     };
 
-    for i in 0..lines.len() {
-        let line = &lines[i];
-
-        let is_match = pattern_re.is_match(&line);
-        if !is_match {
-            continue;
-        }
-
-        let captures = pattern_specific_re.captures(&line).unwrap();
-        let pre = captures.name("pre").unwrap();
-        let match_ = captures.name("match").unwrap();
-        let post = captures.name("post").unwrap();
-
-        let mut contexts = vec![];
-        let start_index = if i > CONTEXT { i - CONTEXT } else { 0 };
-
-        for j in start_index..i {
-            let line = lines[j].to_string();
-            let search_match = SearchMatchContext {
-                line_number: j as i32 + 1,
-                lines: vec![SearchMatchText::Text(line)],
-            };
-
-            contexts.push(search_match);
-        }
-
-        let m = SearchMatchContext {
-            line_number: i as i32 + 1,
-            lines: vec![
-                SearchMatchText::Text(pre.as_str().into()),
-                SearchMatchText::Match(match_.as_str().into()),
-                SearchMatchText::Text(post.as_str().into()),
-            ],
-        };
-
-        contexts.push(m);
-
-        let end_index = if i + CONTEXT + 1 > lines.len() {
-            lines.len()
-        } else {
-            i + CONTEXT + 1
-        };
-        for j in i + 1..end_index {
-            let line = lines[j].to_string();
-            let search_match = SearchMatchContext {
-                line_number: j as i32 + 1,
-                lines: vec![SearchMatchText::Text(line)],
-            };
-
-            contexts.push(search_match);
-        }
-
+    for (start, end) in context_windows(&matching_lines, context, lines.len()) {
+        let contexts: Vec<SearchMatchContext> = (start..end)
+            .map(|i| SearchMatchContext {
+                line_number: i as i32 + 1,
+                lines: highlight(lines[i], &spans[i]),
+            })
+            .collect();
+
+        // The editor opens at the first match in the block.
+        let first_match = (start..end)
+            .find(|&i| !spans[i].is_empty())
+            .unwrap_or(start);
+        let anchor = headings
+            .iter()
+            .take_while(|(line, _)| *line <= first_match)
+            .last()
+            .map(|(_, anchor)| anchor.clone());
         let context = SearchFileMatchContext {
+            line_number: first_match as i32 + 1,
+            anchor,
             html: contexts.to_html(),
             contexts: contexts,
         };
@@ -227,9 +637,57 @@ where
         file_match.contexts.push(context);
     }
 
-    if file_match.contexts.len() > 0 {
-        return Ok(file_match);
+    Ok(file_match)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(query: &str, text: &str) -> Option<f32> {
+        let words: Vec<String> = index::tokenize(query).collect();
+        fuzzy_score(&words, text)
+    }
+
+    #[test]
+    fn highlights_every_match() {
+        let regex = Regex::new("(?i)ab").unwrap();
+        let other = Regex::new("b+c").unwrap();
+        let spans = match_spans("ab x AB abbc", &[&regex, &other]);
+        assert_eq!(spans, vec![(0, 2), (5, 7), (8, 12)]);
+
+        let html = [SearchMatchContext {
+            line_number: 1,
+            lines: highlight("ab x AB abbc", &spans),
+        }]
+        .to_html();
+        assert_eq!(html.matches("<span class=\"match\">").count(), 3);
+        assert!(html.contains("<span class=\"match\">AB</span> "));
+    }
+
+    #[test]
+    fn merges_overlapping_context() {
+        assert_eq!(context_windows(&[2, 5], 3, 100), vec![(0, 9)]);
+        // Windows that touch are merged too.
+        assert_eq!(context_windows(&[0, 7], 3, 100), vec![(0, 11)]);
+        assert_eq!(context_windows(&[0, 10], 3, 12), vec![(0, 4), (7, 12)]);
+        assert_eq!(context_windows(&[4], 0, 12), vec![(4, 5)]);
+    }
+
+    #[test]
+    fn detects_binary_files() {
+        assert!(is_binary(b"PNG\x00\x01"));
+        assert!(!is_binary(b"# Title\n"));
+        assert!(!is_binary(b"\xFF\xFE#\x00"));
     }
 
-    bail!("No match");
+    #[test]
+    fn fuzzy_matches() {
+        assert!(score("notes", "meetings/notes").unwrap() > 1.0);
+        assert!(score("notes", "notes").unwrap() > score("notes", "meetings/notes").unwrap());
+        assert!(score("Meeting Notes", "meetings/notes").unwrap() > 1.0);
+        assert!(score("mtng", "meetings").unwrap() > score("mtng", "my thing").unwrap());
+        assert_eq!(score("xyz", "meetings"), None);
+        assert_eq!(score("", "meetings"), None);
+    }
 }
@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use sha2::{Digest, Sha256};
+
+/// A heading and everything below it, up to the next heading of the same or
+/// a higher level.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Section {
+    pub index: usize,
+    pub level: usize,
+    pub title: String,
+    /// Id of the heading in the rendered page, unique within the page.
+    pub anchor: String,
+    /// Byte range of the section in the markdown file.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Finds all sections of a markdown document, in the order their headings
+/// appear. The index of a section is the index of its heading.
+pub fn sections(content: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = vec![];
+    let mut current_title: Option<String> = None;
+    let mut ids: Vec<Option<String>> = vec![];
+
+    let parser = Parser::new_ext(content, Options::all()).into_offset_iter();
+    for (event, range) in parser {
+        match event {
+            Event::Start(Tag::Heading(level, id, _)) => {
+                let level = level as usize;
+                // Close every open section this heading ends.
+                for section in sections.iter_mut() {
+                    if section.end == content.len() && section.level >= level {
+                        section.end = range.start;
+                    }
+                }
+                sections.push(Section {
+                    index: sections.len(),
+                    level,
+                    title: String::new(),
+                    anchor: String::new(),
+                    start: range.start,
+                    end: content.len(),
+                });
+                ids.push(id.map(|id| id.to_string()));
+                current_title = Some(String::new());
+            }
+            Event::End(Tag::Heading(..)) => {
+                if let (Some(title), Some(section)) = (current_title.take(), sections.last_mut()) {
+                    section.title = title;
+                }
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some(ref mut title) = current_title {
+                    title.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Headings with the same title get a number after their anchor.
+    let mut anchors = HashSet::new();
+    for (section, id) in sections.iter_mut().zip(ids) {
+        let base = id.unwrap_or_else(|| slug(&section.title));
+        let mut anchor = base.clone();
+        let mut number = 1;
+        while !anchors.insert(anchor.clone()) {
+            anchor = format!("{}-{}", base, number);
+            number += 1;
+        }
+        section.anchor = anchor;
+    }
+
+    sections
+}
+
+/// Turns a heading into an anchor, such as `my-heading` for `My heading!`.
+fn slug(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Hash of the whole file, used to detect that a page changed while one of
+/// its sections was being edited.
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Replaces the byte range `start..end` of `content` with `replacement`.
+/// The section keeps its trailing newline, so that the next heading doesn't
+/// end up on the same line.
+pub fn splice(content: &str, start: usize, end: usize, replacement: &str) -> Option<String> {
+    if start > end || end > content.len() {
+        return None;
+    }
+
+    let before = content.get(..start)?;
+    let after = content.get(end..)?;
+
+    let mut spliced = String::with_capacity(content.len() + replacement.len());
+    spliced.push_str(before);
+    spliced.push_str(replacement);
+    if !after.is_empty() && !replacement.is_empty() && !replacement.ends_with('\n') {
+        spliced.push('\n');
+    }
+    spliced.push_str(after);
+    Some(spliced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNBOOK: &str = "intro\n# A\na\n## B\nb\n### C\nc\n## D\nd\n# E\ne\n";
+
+    #[test]
+    fn sections_end_at_same_or_higher_level() {
+        let sections = sections(RUNBOOK);
+        let titles: Vec<&str> = sections.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "B", "C", "D", "E"]);
+
+        let text = |i: usize| &RUNBOOK[sections[i].start..sections[i].end];
+        assert_eq!(text(0), "# A\na\n## B\nb\n### C\nc\n## D\nd\n");
+        assert_eq!(text(1), "## B\nb\n### C\nc\n");
+        assert_eq!(text(2), "### C\nc\n");
+        assert_eq!(text(3), "## D\nd\n");
+        assert_eq!(text(4), "# E\ne\n");
+    }
+
+    #[test]
+    fn headings_in_code_blocks_are_ignored() {
+        let sections = sections("# A\n```\n# not a heading\n```\n# B\n");
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[1].title, "B");
+    }
+
+    #[test]
+    fn anchors_are_unique() {
+        let sections = sections(
+            "# My heading!
+## Notes
+# Notes
+# Custom {#here}
+# ?
+",
+        );
+        let anchors: Vec<&str> = sections.iter().map(|s| s.anchor.as_str()).collect();
+        assert_eq!(
+            anchors,
+            vec!["my-heading", "notes", "notes-1", "here", "section"]
+        );
+    }
+
+    #[test]
+    fn splice_replaces_section() {
+        let section = &sections(RUNBOOK)[3];
+        let spliced = splice(RUNBOOK, section.start, section.end, "## D2\nnew").unwrap();
+        assert_eq!(
+            spliced,
+            "intro\n# A\na\n## B\nb\n### C\nc\n## D2\nnew\n# E\ne\n"
+        );
+    }
+
+    #[test]
+    fn splice_rejects_invalid_ranges() {
+        assert_eq!(splice("abc", 2, 1, ""), None);
+        assert_eq!(splice("abc", 0, 4, ""), None);
+        assert_eq!(splice("æøå", 1, 2, ""), None);
+    }
+}
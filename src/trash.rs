@@ -0,0 +1,163 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::errors::*;
+
+/// Deleted pages are moved into this folder, relative to the wiki root.
+pub const TRASH_DIR: &str = ".simplewiki/trash";
+
+/// Metadata stored next to every deleted page, so that it can be restored to
+/// where it came from.
+#[derive(Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub page: String,
+    pub original_path: PathBuf,
+    pub deleted_at: DateTime<Utc>,
+    /// The user who deleted the page, when authentication is enabled.
+    #[serde(default)]
+    pub deleted_by: Option<String>,
+}
+
+fn trash_dir(wiki_root: &Path) -> PathBuf {
+    wiki_root.join(TRASH_DIR)
+}
+
+fn content_path(wiki_root: &Path, id: &str) -> PathBuf {
+    trash_dir(wiki_root).join(format!("{}.md", id))
+}
+
+fn meta_path(wiki_root: &Path, id: &str) -> PathBuf {
+    trash_dir(wiki_root).join(format!("{}.json", id))
+}
+
+/// Ids come from the url, so make sure they can't point outside the trash.
+fn check_id(id: &str) -> Result<()> {
+    let is_valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !is_valid {
+        bail!("Invalid trash id: {}", id);
+    }
+    Ok(())
+}
+
+/// Moves the markdown file of `page` into the trash.
+pub fn delete(
+    wiki_root: &Path,
+    page: &str,
+    file_path: &Path,
+    deleted_by: Option<&str>,
+) -> Result<TrashEntry> {
+    let deleted_at = Utc::now();
+    let page_id: String = page
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let id = format!("{}-{}", deleted_at.format("%Y%m%d%H%M%S%3f"), page_id);
+
+    let original_path = file_path
+        .strip_prefix(wiki_root)
+        .chain_err(|| "Page is not inside the wiki")?
+        .to_path_buf();
+
+    let entry = TrashEntry {
+        id,
+        page: page.replace('\\', "/"),
+        original_path,
+        deleted_at,
+        deleted_by: deleted_by.map(|user| user.to_string()),
+    };
+
+    fs::create_dir_all(trash_dir(wiki_root)).chain_err(|| "Failed to create trash folder")?;
+
+    let meta = serde_json::to_string_pretty(&entry).chain_err(|| "Failed to serialize")?;
+    let mut file = File::create(meta_path(wiki_root, &entry.id))
+        .chain_err(|| "Failed to create trash metadata")?;
+    file.write_all(meta.as_bytes())
+        .chain_err(|| "Failed to write trash metadata")?;
+
+    if let Err(e) = fs::rename(file_path, content_path(wiki_root, &entry.id)) {
+        // Don't leave metadata behind for a page that never made it.
+        let _ = fs::remove_file(meta_path(wiki_root, &entry.id));
+        return Err(e).chain_err(|| "Failed to move page to trash");
+    }
+
+    Ok(entry)
+}
+
+fn read_entry(path: &Path) -> Result<TrashEntry> {
+    let file = File::open(path).chain_err(|| "Failed to open trash metadata")?;
+    serde_json::from_reader(file).chain_err(|| "Invalid trash metadata")
+}
+
+pub fn get(wiki_root: &Path, id: &str) -> Result<TrashEntry> {
+    check_id(id)?;
+    read_entry(&meta_path(wiki_root, id))
+}
+
+/// Returns all pages in the trash, most recently deleted first. Pages whose
+/// metadata can't be read are left out, so that one of them doesn't hide the
+/// rest.
+pub fn list(wiki_root: &Path) -> Result<Vec<TrashEntry>> {
+    let dir = trash_dir(wiki_root);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+    for file in fs::read_dir(&dir).chain_err(|| "Failed to read trash folder")? {
+        let path = match file {
+            Ok(file) => file.path(),
+            Err(e) => {
+                println!("Skipping trash entry: {}", e);
+                continue;
+            }
+        };
+        if path.extension().map_or(false, |ext| ext == "json") {
+            match read_entry(&path) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => println!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+    Ok(entries)
+}
+
+/// Moves a page out of the trash, back to its original location. Refuses to
+/// overwrite a page that has been created there since.
+pub fn restore(wiki_root: &Path, id: &str) -> Result<TrashEntry> {
+    check_id(id)?;
+    let entry = read_entry(&meta_path(wiki_root, id))?;
+    let target = wiki_root.join(&entry.original_path);
+
+    if target.exists() {
+        bail!("A page already exists at {}", entry.page);
+    }
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).chain_err(|| "Failed to create page folder")?;
+    }
+
+    fs::rename(content_path(wiki_root, id), &target).chain_err(|| "Failed to restore page")?;
+    fs::remove_file(meta_path(wiki_root, id)).chain_err(|| "Failed to remove trash metadata")?;
+
+    Ok(entry)
+}
+
+/// Permanently deletes a page from the trash.
+pub fn purge(wiki_root: &Path, id: &str) -> Result<TrashEntry> {
+    check_id(id)?;
+    let entry = read_entry(&meta_path(wiki_root, id))?;
+
+    fs::remove_file(content_path(wiki_root, id)).chain_err(|| "Failed to purge page")?;
+    fs::remove_file(meta_path(wiki_root, id)).chain_err(|| "Failed to remove trash metadata")?;
+
+    Ok(entry)
+}
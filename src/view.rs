@@ -46,7 +46,7 @@ impl ViewFinder {
     pub fn new(path: PathBuf) -> Self {
         ViewFinder {
             path: path,
-            page_name_regex: Regex::new(r"(.*)\.md").unwrap(),
+            page_name_regex: Regex::new(r"(.*)\.md$").unwrap(),
         }
     }
 
@@ -122,6 +122,17 @@ impl ViewFinder {
     }
 }
 
+/// Removes the views that `keep` rejects, and the groups that are left empty.
+pub fn retain_views<F>(view_groups: &mut Vec<ViewGroup>, keep: F)
+where
+    F: Fn(&View) -> bool,
+{
+    for group in view_groups.iter_mut() {
+        group.views.retain(|view| keep(view));
+    }
+    view_groups.retain(|group| !group.views.is_empty());
+}
+
 #[derive(Serialize)]
 pub struct PrevNextResult {
     pub prev: Option<View>,
@@ -258,4 +269,32 @@ mod tests {
         assert_eq!(res.prev.map(|x| x.name), Some("5".into()));
         assert_eq!(res.next.map(|x| x.name), None);
     }
+
+    #[test]
+    fn hidden_views_are_skipped() {
+        let view = |file_name: &str| View {
+            name: file_name.into(),
+            file_name: file_name.into(),
+        };
+        let mut groups = vec![
+            ViewGroup {
+                key: "a".into(),
+                views: vec![view("a/1"), view("a/2")],
+            },
+            ViewGroup {
+                key: "hr".into(),
+                views: vec![view("hr/1")],
+            },
+            ViewGroup {
+                key: "b".into(),
+                views: vec![view("b/1")],
+            },
+        ];
+
+        retain_views(&mut groups, |view| !view.file_name.starts_with("hr/"));
+        assert_eq!(groups.len(), 2);
+
+        let res = find_prev_next(&groups, "b/1");
+        assert_eq!(res.prev.map(|x| x.file_name), Some("a/2".into()));
+    }
 }
@@ -0,0 +1,307 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use notify::{op, raw_watcher, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Changes are reported once no file has changed for this long, unless
+/// `--debounce` says otherwise.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Editor swap and backup files, and temporary files.
+const IGNORED_SUFFIXES: &[&str] = &[".swp", ".swo", ".swx", ".tmp", ".bak", ".crswap", "~"];
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// This file or folder was created.
+    Created(PathBuf),
+    /// The content of this file changed.
+    Changed(PathBuf),
+    /// This file or folder was deleted.
+    Removed(PathBuf),
+    /// This file or folder was moved from the first path to the second.
+    Renamed(PathBuf, PathBuf),
+    /// Events were lost, so anything may have changed.
+    Everything,
+}
+
+impl Change {
+    /// The files or folders that changed.
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Change::Created(path) | Change::Changed(path) | Change::Removed(path) => vec![path],
+            Change::Renamed(from, to) => vec![from, to],
+            Change::Everything => vec![],
+        }
+    }
+}
+
+/// Turns an event from the watcher into a change. A rename is reported as
+/// two events with the same cookie, the first of which is kept in
+/// `renamed_from` until the second arrives.
+fn change(event: RawEvent, renamed_from: &mut Option<(u32, PathBuf)>) -> Option<Change> {
+    let op = match event.op {
+        Ok(op) => op,
+        Err(e) => {
+            println!("watch error: {:?} {:?}", e, event.path);
+            return None;
+        }
+    };
+    if op.contains(op::RESCAN) {
+        return Some(Change::Everything);
+    }
+    let path = event.path?;
+
+    if op.contains(op::RENAME) {
+        match (event.cookie, renamed_from.take()) {
+            (Some(cookie), Some((from_cookie, from))) if cookie == from_cookie => {
+                Some(Change::Renamed(from, path))
+            }
+            (Some(cookie), _) => {
+                *renamed_from = Some((cookie, path));
+                None
+            }
+            // Moved out of the wiki folder.
+            (None, _) => Some(Change::Removed(path)),
+        }
+    } else if op.contains(op::CREATE) {
+        Some(Change::Created(path))
+    } else if op.contains(op::REMOVE) {
+        Some(Change::Removed(path))
+    } else if op.intersects(op::WRITE | op::CLOSE_WRITE | op::CHMOD) {
+        Some(Change::Changed(path))
+    } else {
+        None
+    }
+}
+
+/// `path` relative to the wiki folder, which the watcher may report
+/// canonicalized.
+pub fn relative_path(wiki_root: &Path, path: &Path) -> Option<PathBuf> {
+    if let Ok(relative_path) = path.strip_prefix(wiki_root) {
+        return Some(relative_path.to_path_buf());
+    }
+    let root = fs::canonicalize(wiki_root).ok()?;
+    path.strip_prefix(root).ok().map(Path::to_path_buf)
+}
+
+/// Files that never change the wiki: anything in a hidden folder such as
+/// `.git` or `.simplewiki`, hidden files such as the temporary files pages
+/// are saved through, and editor swap and backup files.
+pub fn is_ignored(relative_path: &Path) -> bool {
+    let is_hidden = relative_path.components().any(|component| match component {
+        Component::Normal(part) => part.to_str().map_or(false, |part| part.starts_with('.')),
+        _ => false,
+    });
+    let name = relative_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    is_hidden
+        || IGNORED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        || (name.starts_with('#') && name.ends_with('#'))
+        // Vim checks that it can write to a folder with this file.
+        || name == "4913"
+}
+
+/// Whether `relative_path` is in the `.attachments` folder of a page.
+pub fn is_attachment(relative_path: &Path) -> bool {
+    relative_path
+        .parent()
+        .and_then(Path::extension)
+        .map_or(false, |ext| ext == "attachments")
+}
+
+/// Pages, attachments and their folders, and other folders, which are told
+/// apart from files by their missing extension once they are gone.
+fn is_wiki_file(path: &Path, relative_path: &Path) -> bool {
+    match relative_path.extension() {
+        Some(ext) => {
+            ext == "md" || ext == "attachments" || is_attachment(relative_path) || path.is_dir()
+        }
+        None => true,
+    }
+}
+
+/// Whether changes to `path` are reported. Says why not in verbose mode.
+fn is_reported(wiki_root: &Path, path: &Path, verbose: bool) -> bool {
+    let reported = relative_path(wiki_root, path).map_or(false, |relative| {
+        !is_ignored(&relative) && is_wiki_file(path, &relative)
+    });
+    if !reported && verbose {
+        println!("Ignoring change to {}", path.display());
+    }
+    reported
+}
+
+/// Keeps the changes to the wiki's pages, attachments and folders from a
+/// burst of events, each reported once.
+fn coalesce(wiki_root: &Path, events: Vec<RawEvent>, verbose: bool) -> Vec<Change> {
+    let mut changes: Vec<Change> = vec![];
+    let mut renamed_from = None;
+    let events = events
+        .into_iter()
+        .filter_map(|event| change(event, &mut renamed_from));
+    for change in events {
+        let change = match change {
+            Change::Everything => return vec![Change::Everything],
+            // Saving through a temporary file shows up as a rename.
+            Change::Renamed(from, to) => match (
+                is_reported(wiki_root, &from, verbose),
+                is_reported(wiki_root, &to, verbose),
+            ) {
+                (true, true) => Change::Renamed(from, to),
+                (false, true) => Change::Created(to),
+                (true, false) => Change::Removed(from),
+                (false, false) => continue,
+            },
+            Change::Created(path) | Change::Changed(path) | Change::Removed(path)
+                if !is_reported(wiki_root, &path, verbose) =>
+            {
+                continue
+            }
+            change => change,
+        };
+
+        let is_duplicate = changes.iter().any(|seen| match (seen, &change) {
+            (Change::Created(created), Change::Changed(changed)) => created == changed,
+            (seen, change) => seen == change,
+        });
+        if is_duplicate {
+            if verbose {
+                println!("Ignoring repeated {:?}", change);
+            }
+        } else {
+            changes.push(change);
+        }
+    }
+    changes
+}
+
+/// Watches the wiki folder from a background thread. Once no file has
+/// changed for `debounce`, calls `on_change` with what changed.
+pub fn watch<F>(wiki_path: &str, debounce: Duration, verbose: bool, on_change: F)
+where
+    F: Fn(Vec<Change>) + Send + 'static,
+{
+    let wiki_path = wiki_path.to_owned();
+    thread::spawn(move || {
+        let (watcher_s, watcher_r) = mpsc::channel();
+        let mut watcher: RecommendedWatcher = raw_watcher(watcher_s).expect("Create watcher");
+
+        watcher.watch(&wiki_path, RecursiveMode::Recursive).unwrap();
+
+        loop {
+            let mut events = match watcher_r.recv() {
+                Ok(event) => vec![event],
+                Err(e) => {
+                    println!("watch error: {:?}", e);
+                    return;
+                }
+            };
+
+            // The watcher reports every event as it happens, so the events
+            // of a burst are collected until it is over.
+            let mut quiet_at = Instant::now() + debounce;
+            loop {
+                match watcher_r.recv_timeout(quiet_at.saturating_duration_since(Instant::now())) {
+                    Ok(event) => {
+                        events.push(event);
+                        quiet_at = Instant::now() + debounce;
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+
+            let changes = coalesce(Path::new(&wiki_path), events, verbose);
+            if !changes.is_empty() {
+                on_change(changes);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::Op;
+
+    fn event(op: Op, path: PathBuf) -> RawEvent {
+        RawEvent {
+            path: Some(path),
+            op: Ok(op),
+            cookie: None,
+        }
+    }
+
+    fn rename(cookie: u32, path: PathBuf) -> RawEvent {
+        RawEvent {
+            cookie: Some(cookie),
+            ..event(op::RENAME, path)
+        }
+    }
+
+    #[test]
+    fn ignored_files() {
+        assert!(is_ignored(Path::new(".git/index")));
+        assert!(is_ignored(Path::new("folder/.page.md.123.0.tmp")));
+        assert!(is_ignored(Path::new("folder/.page.md.swp")));
+        assert!(is_ignored(Path::new("page.md~")));
+        assert!(is_ignored(Path::new("#page.md#")));
+        assert!(is_ignored(Path::new("page.md.bak")));
+        assert!(is_ignored(Path::new("4913")));
+        assert!(!is_ignored(Path::new("folder/page.md")));
+        assert!(!is_ignored(Path::new("page.attachments/image.png")));
+    }
+
+    #[test]
+    fn bursts_are_coalesced() {
+        let root = Path::new("/wiki");
+        let events = vec![
+            event(op::CREATE, root.join(".page.md.1.0.tmp")),
+            event(op::WRITE, root.join(".page.md.1.0.tmp")),
+            rename(7, root.join(".page.md.1.0.tmp")),
+            rename(7, root.join("page.md")),
+            event(op::WRITE, root.join("other.md")),
+            event(op::CLOSE_WRITE, root.join("other.md")),
+            event(op::WRITE, root.join("notes.txt")),
+            event(op::CREATE, root.join("page.attachments/image.png")),
+            event(op::CREATE, root.join("folder")),
+            event(op::WRITE, root.join(".git/index")),
+            rename(9, root.join("new.md")),
+            rename(9, root.join("newer.md")),
+            event(op::RENAME, root.join("gone.md")),
+        ];
+
+        assert_eq!(
+            coalesce(root, events, false),
+            vec![
+                Change::Created(root.join("page.md")),
+                Change::Changed(root.join("other.md")),
+                Change::Created(root.join("page.attachments/image.png")),
+                Change::Created(root.join("folder")),
+                Change::Renamed(root.join("new.md"), root.join("newer.md")),
+                Change::Removed(root.join("gone.md")),
+            ]
+        );
+        assert_eq!(
+            coalesce(
+                root,
+                vec![
+                    event(op::WRITE, root.join("a.md")),
+                    RawEvent {
+                        path: None,
+                        op: Ok(op::RESCAN),
+                        cookie: None,
+                    }
+                ],
+                false
+            ),
+            vec![Change::Everything]
+        );
+    }
+}
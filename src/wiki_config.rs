@@ -0,0 +1,50 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::errors::*;
+
+/// Settings kept with the wiki, relative to the wiki root. Command line
+/// flags override them.
+pub const CONFIG_FILE: &str = ".simplewiki/config.json";
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WikiConfig {
+    /// Serve the wiki without the ability to change it, like `--read-only`.
+    pub read_only: bool,
+}
+
+/// Reads the settings of the wiki. A wiki without a config file has the
+/// default settings.
+pub fn load(wiki_root: &Path) -> Result<WikiConfig> {
+    let path = wiki_root.join(CONFIG_FILE);
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content)
+            .chain_err(|| format!("Invalid config file {}", path.display())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(WikiConfig::default()),
+        Err(e) => Err(e).chain_err(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn missing_or_given_settings() {
+        let root = env::temp_dir().join(format!("simplewiki-config-{}", process::id()));
+        fs::create_dir_all(root.join(".simplewiki")).unwrap();
+        assert_eq!(load(&root).unwrap(), WikiConfig::default());
+
+        fs::write(root.join(CONFIG_FILE), r#"{"read_only": true}"#).unwrap();
+        assert!(load(&root).unwrap().read_only);
+
+        fs::write(root.join(CONFIG_FILE), r#"{"readonly": true}"#).unwrap();
+        assert!(load(&root).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}